@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use uuid::Uuid;
+
+use super::nodes::{INode, NameNode, Node};
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum CacheKey {
+    Node(Node),
+    Name(Uuid),
+    // Ids of every name node going by a name, as kept in namenodes/
+    NameSet(OsString),
+}
+
+#[derive(Clone)]
+pub enum Cached {
+    Node(INode),
+    Name(NameNode),
+    NameSet(BTreeSet<Uuid>),
+}
+
+struct Entry {
+    value: Cached,
+    dirty: bool,
+}
+
+/// Recently used nodes, kept in memory so that they don't have to be read from the store again.
+///
+/// Writes only mark the entry dirty, it's up to the owner to write back whatever `insert` evicts
+/// and whatever `take_dirty` hands out.
+pub struct NodeCache {
+    entries: LruCache<CacheKey, Entry>,
+    // Inode numbers of the cached nodes, their inodes/ links might not have been written yet
+    inos: BTreeMap<u64, Node>,
+}
+
+impl NodeCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            inos: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<Cached> {
+        self.entries.get(key).map(|entry| entry.value.clone())
+    }
+
+    pub fn node_of(&self, ino: u64) -> Option<Node> {
+        self.inos.get(&ino).cloned()
+    }
+
+    /// Caches a value, dirty ones are written back later on. Returns the dirty entry that had to
+    /// make room for it, if any.
+    pub fn insert(
+        &mut self,
+        key: CacheKey,
+        value: Cached,
+        dirty: bool,
+    ) -> Option<(CacheKey, Cached)> {
+        if let Cached::Node(inode) = &value {
+            self.inos.insert(inode.attr().inode, inode.to_node());
+        }
+
+        // Replacing an entry doesn't make it any cleaner
+        let dirty = dirty || self.entries.peek(&key).is_some_and(|e| e.dirty);
+        match self.entries.push(key.clone(), Entry { value, dirty }) {
+            Some((evicted, entry)) if evicted != key => {
+                self.forget_ino(&entry.value);
+                entry.dirty.then_some((evicted, entry.value))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.pop(key) {
+            self.forget_ino(&entry.value);
+        }
+    }
+
+    /// Everything that has changed since it was last written back, marking it clean.
+    pub fn take_dirty(&mut self) -> Vec<(CacheKey, Cached)> {
+        let mut dirty = Vec::new();
+        for (key, entry) in self.entries.iter_mut().filter(|(_, e)| e.dirty) {
+            entry.dirty = false;
+            dirty.push((key.clone(), entry.value.clone()));
+        }
+
+        dirty
+    }
+
+    /// Forgets everything, dirty entries have to be written back beforehand.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.inos.clear();
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &CacheKey> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    fn forget_ino(&mut self, value: &Cached) {
+        if let Cached::Node(inode) = value {
+            let ino = inode.attr().inode;
+            // A rehashed file is cached under its new hash by now
+            if self.inos.get(&ino) == Some(&inode.to_node()) {
+                self.inos.remove(&ino);
+            }
+        }
+    }
+}
@@ -3,15 +3,28 @@ use libc::{getgid, getuid};
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     fs::remove_file,
     os::unix::fs::symlink,
     path::PathBuf,
+    str::FromStr,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use uuid::Uuid;
 
 pub const BLOCK_SIZE: u64 = 512;
 
+// Bumped whenever the on-disk layout of the store changes
+pub const FORMAT_VERSION: u32 = 5;
+
+// Virtual extended attribute listing the tags a file is in, one per line
+pub const TAGS_XATTR: &str = "user.tagfs.tags";
+
+// Listings tell apart several names that are the same as `name~<short id>`
+pub const NAME_SUFFIX_SEPARATOR: u8 = b'~';
+pub const SHORT_ID_LEN: usize = 8;
+
 // Helper time functions section
 pub fn time_now() -> (i64, u32) {
     time_from_system_time(&SystemTime::now())
@@ -37,14 +50,15 @@ pub fn time_from_system_time(system_time: &SystemTime) -> (i64, u32) {
 }
 
 pub fn rewrite_symlink(path: PathBuf, symlink_path: PathBuf) {
-    if symlink_path.exists() {
+    // The old target might be gone already, which exists() would follow the link to
+    if symlink_path.symlink_metadata().is_ok() {
         remove_file(&symlink_path).unwrap()
     };
     symlink(path, symlink_path).unwrap();
 }
 
 // Hash section
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize, Serialize, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Deserialize, Serialize, Debug)]
 pub struct Hash256 {
     #[serde(with = "from_string")]
     pub code: String,
@@ -59,7 +73,7 @@ mod from_string {
     where
         D: Deserializer<'de>,
     {
-        Ok(<String>::deserialize(d)?)
+        <String>::deserialize(d)
     }
 
     pub fn serialize<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
@@ -89,6 +103,43 @@ impl Display for Hash256 {
     }
 }
 
+/// What a file and its content are stored under. The hash only covers the content, so files
+/// that happen to have the same content are told apart by their inode number.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Deserialize, Serialize, Debug)]
+pub struct FileKey {
+    pub hash: Hash256,
+    pub ino: u64,
+}
+
+impl Display for FileKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.hash, self.ino)
+    }
+}
+
+impl FromStr for FileKey {
+    type Err = ();
+
+    // The other way around from Display, `<hash>-<ino>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (code, ino) = s.rsplit_once('-').ok_or(())?;
+        Ok(Self {
+            hash: Hash256 {
+                code: code.to_string(),
+            },
+            ino: ino.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+// Superblock section
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Superblock {
+    pub version: u32,
+    pub root: Uuid,
+    pub inode_next: u64,
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
 pub enum FileKind {
     File,
@@ -106,10 +157,9 @@ impl From<FileKind> for fuser::FileType {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct InodeAttributes {
     pub inode: u64,
-    pub open_file_handles: u64, // Ref count of open file handles to this inode
     pub size: u64,
     pub last_accessed: (i64, u32),
     pub last_modified: (i64, u32),
@@ -119,7 +169,7 @@ pub struct InodeAttributes {
     pub hardlinks: u32,
     pub uid: u32,
     pub gid: u32,
-    //pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
 impl From<InodeAttributes> for fuser::FileAttr {
@@ -127,7 +177,7 @@ impl From<InodeAttributes> for fuser::FileAttr {
         fuser::FileAttr {
             ino: attrs.inode,
             size: attrs.size,
-            blocks: (attrs.size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            blocks: attrs.size.div_ceil(BLOCK_SIZE),
             atime: system_time_from_time(attrs.last_accessed.0, attrs.last_accessed.1),
             mtime: system_time_from_time(attrs.last_modified.0, attrs.last_modified.1),
             ctime: system_time_from_time(
@@ -151,7 +201,6 @@ impl InodeAttributes {
     pub fn new_file_attr(inode: u64, kind: FileKind, mode: u16) -> Self {
         Self {
             inode,
-            open_file_handles: 0,
             size: 0,
             last_accessed: time_now(),
             last_modified: time_now(),
@@ -161,6 +210,7 @@ impl InodeAttributes {
             hardlinks: 0,
             uid: unsafe { getuid() },
             gid: unsafe { getgid() },
+            xattrs: BTreeMap::new(),
         }
     }
 }
@@ -0,0 +1,547 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io;
+
+use sha3::{Digest, Sha3_256};
+use uuid::Uuid;
+
+use crate::fs::defs::{FileKey, FORMAT_VERSION};
+use crate::fs::nodes::{INode, NameNode, Node, TagNode};
+use crate::fs::store::NodeStore;
+
+/// What `fsck` came across.
+#[derive(Default)]
+pub struct Report {
+    pub problems: usize,
+    pub repaired: usize,
+}
+
+/// Checks that the nodes in an unmounted store agree with each other, fixing what it can when
+/// asked to. Every problem is printed as it's found, an error means the repairs couldn't be
+/// written.
+pub fn fsck(store: &dyn NodeStore, repair: bool) -> io::Result<Report> {
+    let mut fsck = Fsck::load(store, repair);
+    fsck.check()?;
+    if repair {
+        fsck.write_back()?;
+    }
+
+    Ok(fsck.report)
+}
+
+struct Fsck<'a> {
+    store: &'a dyn NodeStore,
+    repair: bool,
+    report: Report,
+    nodes: BTreeMap<Node, INode>,
+    name_nodes: BTreeMap<Uuid, NameNode>,
+    // What has to be written back once everything is checked
+    changed_nodes: BTreeSet<Node>,
+    removed_nodes: Vec<INode>,
+    changed_names: BTreeSet<Uuid>,
+    removed_names: BTreeSet<Uuid>,
+    removed_blobs: Vec<FileKey>,
+    renamed_blobs: Vec<(FileKey, FileKey)>,
+    rewrite_superblock: bool,
+}
+
+// Tags name themselves and their parent without storing those names anywhere
+fn is_own_name(tag_node: &TagNode, id: &Uuid) -> bool {
+    [".", ".."]
+        .iter()
+        .any(|name| tag_node.named(name.as_ref()).any(|own| own == id))
+}
+
+impl<'a> Fsck<'a> {
+    fn load(store: &'a dyn NodeStore, repair: bool) -> Self {
+        let nodes = store
+            .list_tags()
+            .into_iter()
+            .map(Node::Tag)
+            .chain(store.list_symlinks().into_iter().map(Node::Symlink))
+            .chain(store.list_files().into_iter().map(Node::File))
+            .filter_map(|node| Some((node.clone(), store.load_node(&node)?)))
+            .collect();
+        let name_nodes = store
+            .list_name_nodes()
+            .into_iter()
+            .filter_map(|id| Some((id, store.load_name_node(&id)?)))
+            .collect();
+
+        Self {
+            store,
+            repair,
+            report: Report::default(),
+            nodes,
+            name_nodes,
+            changed_nodes: BTreeSet::new(),
+            removed_nodes: Vec::new(),
+            changed_names: BTreeSet::new(),
+            removed_names: BTreeSet::new(),
+            removed_blobs: Vec::new(),
+            renamed_blobs: Vec::new(),
+            rewrite_superblock: false,
+        }
+    }
+
+    // Returns whether the problem is to be repaired
+    fn problem(&mut self, message: String, repairable: bool) -> bool {
+        self.report.problems += 1;
+        let repair = self.repair && repairable;
+        if repair {
+            self.report.repaired += 1;
+            println!("{message}, repaired");
+        } else if self.repair {
+            println!("{message}, can't be repaired");
+        } else {
+            println!("{message}");
+        }
+
+        repair
+    }
+
+    fn remove_name(&mut self, id: &Uuid) {
+        self.name_nodes.remove(id);
+        self.changed_names.remove(id);
+        self.removed_names.insert(*id);
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        let root = match self.store.read_superblock() {
+            Ok(Some(superblock)) if superblock.version == FORMAT_VERSION => superblock.root,
+            Ok(Some(superblock)) => {
+                self.problem(
+                    format!("Store has format version {}", superblock.version),
+                    false,
+                );
+                return Ok(());
+            }
+            Ok(None) => {
+                self.problem("There's no superblock".to_string(), false);
+                return Ok(());
+            }
+            Err(e) => {
+                self.problem(format!("The superblock can't be read: {e}"), false);
+                return Ok(());
+            }
+        };
+        if !self.nodes.contains_key(&Node::Tag(root)) {
+            self.problem(format!("Root tag {root} is missing"), false);
+            return Ok(());
+        }
+
+        self.check_hashes()?;
+        self.check_name_links();
+        self.check_dir_links();
+        self.check_back_links();
+        self.check_unnamed(root);
+        self.check_inodes();
+
+        Ok(())
+    }
+
+    fn check_hashes(&mut self) -> io::Result<()> {
+        let mut hasher = Sha3_256::new();
+        let files: Vec<Node> = self
+            .nodes
+            .keys()
+            .filter(|node| matches!(node, Node::File(_)))
+            .cloned()
+            .collect();
+
+        for node in files {
+            let INode::File(mut file_node) = self.nodes[&node].clone() else {
+                continue;
+            };
+            let old_key = file_node.key();
+
+            let mut content = match self.store.blob_reader(&old_key) {
+                Ok(content) => content,
+                Err(_) => {
+                    if !self.problem(format!("{node} has no content"), true) {
+                        continue;
+                    }
+                    self.store.create_blob(&old_key)?;
+                    self.store.blob_reader(&old_key)?
+                }
+            };
+            if let Err(e) = file_node.calculate_hashes(&mut hasher, &mut content) {
+                self.problem(format!("{node} can't be read: {e}"), false);
+                continue;
+            }
+            if file_node.key() == old_key
+                || !self.problem(format!("{node} doesn't match its content"), true)
+            {
+                continue;
+            }
+
+            // The file goes by its new key from here on, names included
+            let new_node = Node::File(file_node.key());
+            for name_node in self.name_nodes.values_mut() {
+                if name_node.link == node {
+                    name_node.link = new_node.clone();
+                    self.changed_names.insert(name_node.id);
+                }
+            }
+            self.renamed_blobs.push((old_key, file_node.key()));
+            let old = self.nodes.remove(&node).unwrap();
+            self.changed_nodes.remove(&node);
+            self.removed_nodes.push(old);
+            self.nodes.insert(new_node.clone(), INode::File(file_node));
+            self.changed_nodes.insert(new_node);
+        }
+
+        Ok(())
+    }
+
+    fn check_name_links(&mut self) {
+        let dangling: Vec<NameNode> = self
+            .name_nodes
+            .values()
+            .filter(|n| !self.nodes.contains_key(&n.link))
+            .cloned()
+            .collect();
+        for name_node in dangling {
+            if self.problem(
+                format!(
+                    "Name {:?} ({}) links to {}, which doesn't exist",
+                    name_node.name, name_node.id, name_node.link
+                ),
+                true,
+            ) {
+                self.remove_name(&name_node.id);
+            }
+        }
+    }
+
+    fn check_dir_links(&mut self) {
+        let tags: Vec<Node> = self
+            .nodes
+            .keys()
+            .filter(|node| matches!(node, Node::Tag(_)))
+            .cloned()
+            .collect();
+
+        let mut in_tags = BTreeSet::new();
+        for node in tags {
+            let INode::Tag(mut tag_node) = self.nodes[&node].clone() else {
+                continue;
+            };
+            let mut changed = false;
+
+            for id in tag_node.dir_links.clone() {
+                if is_own_name(&tag_node, &id) {
+                    continue;
+                }
+                if !self.name_nodes.contains_key(&id) {
+                    if self.problem(format!("{node} holds name {id}, which doesn't exist"), true) {
+                        tag_node.dir_links.remove(&id);
+                        changed = true;
+                    }
+                    continue;
+                }
+                in_tags.insert(id);
+            }
+
+            // The index by name has to hold exactly what's in the tag
+            let mut names: BTreeMap<OsString, BTreeSet<Uuid>> = tag_node
+                .names
+                .iter()
+                .filter(|(name, _)| *name == "." || *name == "..")
+                .map(|(name, ids)| (name.clone(), ids.clone()))
+                .collect();
+            for id in &tag_node.dir_links {
+                if let Some(name_node) = self.name_nodes.get(id) {
+                    names.entry(name_node.name.clone()).or_default().insert(*id);
+                }
+            }
+            if names != tag_node.names
+                && self.problem(format!("{node} has its names indexed wrong"), true)
+            {
+                tag_node.names = names;
+                changed = true;
+            }
+
+            if changed {
+                self.nodes.insert(node.clone(), INode::Tag(tag_node));
+                self.changed_nodes.insert(node);
+            }
+        }
+
+        let loose: Vec<NameNode> = self
+            .name_nodes
+            .values()
+            .filter(|n| !in_tags.contains(&n.id))
+            .cloned()
+            .collect();
+        for name_node in loose {
+            if self.problem(
+                format!(
+                    "Name {:?} ({}) isn't in any tag",
+                    name_node.name, name_node.id
+                ),
+                true,
+            ) {
+                self.remove_name(&name_node.id);
+            }
+        }
+    }
+
+    fn check_back_links(&mut self) {
+        let mut expected: BTreeMap<Node, BTreeSet<Uuid>> = BTreeMap::new();
+        for name_node in self.name_nodes.values() {
+            expected
+                .entry(name_node.link.clone())
+                .or_default()
+                .insert(name_node.id);
+        }
+
+        let nodes: Vec<Node> = self.nodes.keys().cloned().collect();
+        for node in nodes {
+            let expected = expected.remove(&node).unwrap_or_default();
+            let inode = self.nodes.get_mut(&node).unwrap();
+            let back_links: BTreeSet<Uuid> = inode.back_links().iter().copied().collect();
+            let mut changed = false;
+
+            if back_links != expected
+                && self.problem(
+                    format!(
+                        "{node} links back to {} names instead of {}",
+                        back_links.len(),
+                        expected.len()
+                    ),
+                    true,
+                )
+            {
+                let inode = self.nodes.get_mut(&node).unwrap();
+                let back_links = inode.back_links_mut();
+                back_links.retain(|id| expected.contains(id));
+                for id in &expected {
+                    if !back_links.contains(id) {
+                        back_links.push(*id);
+                    }
+                }
+                changed = true;
+            }
+
+            // Tags count their subtags in their own way
+            let inode = &self.nodes[&node];
+            let (hardlinks, names) = (inode.attr().hardlinks, inode.back_links().len() as u32);
+            if !matches!(node, Node::Tag(_))
+                && hardlinks != names
+                && self.problem(
+                    format!("{node} counts {hardlinks} hard links instead of {names}"),
+                    true,
+                )
+            {
+                self.nodes.get_mut(&node).unwrap().attr_mut().hardlinks = names;
+                changed = true;
+            }
+
+            if changed {
+                self.changed_nodes.insert(node);
+            }
+        }
+    }
+
+    // Files nobody can get to anymore are gone for good, tags are put back in the root
+    fn check_unnamed(&mut self, root: Uuid) {
+        let unnamed: Vec<Node> = self
+            .nodes
+            .iter()
+            .filter(|(node, inode)| **node != Node::Tag(root) && inode.back_links().is_empty())
+            .map(|(node, _)| node.clone())
+            .collect();
+
+        for node in unnamed {
+            if !self.problem(format!("{node} doesn't have any names"), true) {
+                continue;
+            }
+
+            if let Node::Tag(id) = node {
+                let name_node = NameNode::new(OsString::from(id.to_string()), node.clone());
+                let Some(INode::Tag(root_node)) = self.nodes.get_mut(&Node::Tag(root)) else {
+                    continue;
+                };
+                root_node.add_file(&name_node);
+                self.nodes.get_mut(&node).unwrap().add_back_link(&name_node);
+                self.changed_names.insert(name_node.id);
+                self.name_nodes.insert(name_node.id, name_node);
+                self.changed_nodes.insert(Node::Tag(root));
+                self.changed_nodes.insert(node);
+            } else {
+                let inode = self.nodes.remove(&node).unwrap();
+                self.changed_nodes.remove(&node);
+                if let Node::File(key) = &node {
+                    self.removed_blobs.push(key.clone());
+                }
+                self.removed_nodes.push(inode);
+            }
+        }
+    }
+
+    fn check_inodes(&mut self) {
+        let mut owners: BTreeMap<u64, Vec<Node>> = BTreeMap::new();
+        for (node, inode) in &self.nodes {
+            owners
+                .entry(inode.attr().inode)
+                .or_default()
+                .push(node.clone());
+        }
+
+        for (ino, nodes) in &owners {
+            if nodes.len() > 1 {
+                self.problem(
+                    format!("Inode number {ino} is taken by {} nodes", nodes.len()),
+                    false,
+                );
+            } else if self.store.node_of(*ino).as_ref() != Some(&nodes[0])
+                && !self.changed_nodes.contains(&nodes[0])
+                && self.problem(
+                    format!("Inode number {ino} doesn't lead to {}", nodes[0]),
+                    true,
+                )
+            {
+                // Storing the node again points its inode number at it
+                self.changed_nodes.insert(nodes[0].clone());
+            }
+        }
+
+        if let (Some(max), Ok(Some(superblock))) =
+            (owners.keys().last(), self.store.read_superblock())
+        {
+            if superblock.inode_next <= *max
+                && self.problem(
+                    format!(
+                        "Next inode number {} is taken already",
+                        superblock.inode_next
+                    ),
+                    true,
+                )
+            {
+                self.rewrite_superblock = true;
+            }
+        }
+    }
+
+    fn write_back(&mut self) -> io::Result<()> {
+        for inode in &self.removed_nodes {
+            self.store.remove_node(inode)?;
+        }
+        for key in &self.removed_blobs {
+            self.store.remove_blob(key)?;
+        }
+        for (from, to) in &self.renamed_blobs {
+            self.store.rename_blob(from, to)?;
+        }
+        for node in &self.changed_nodes {
+            self.store.store_node(&self.nodes[node])?;
+        }
+
+        for id in &self.removed_names {
+            self.store.remove_name_node(id)?;
+        }
+        for id in &self.changed_names {
+            self.store.store_name_node(&self.name_nodes[id])?;
+        }
+
+        // The index of names is rebuilt from scratch, it's cheap enough
+        let mut name_sets: BTreeMap<OsString, BTreeSet<Uuid>> = self
+            .store
+            .list_names()
+            .into_iter()
+            .map(|name| (name, BTreeSet::new()))
+            .collect();
+        for name_node in self.name_nodes.values() {
+            name_sets
+                .entry(name_node.name.clone())
+                .or_default()
+                .insert(name_node.id);
+        }
+        for (name, ids) in name_sets {
+            if self.store.load_name_set(&name) != ids {
+                self.store.store_name_set(&name, &ids)?;
+            }
+        }
+
+        if self.rewrite_superblock {
+            let mut superblock = self.store.read_superblock()?.unwrap();
+            superblock.inode_next = self
+                .nodes
+                .values()
+                .map(|inode| inode.attr().inode + 1)
+                .max()
+                .unwrap_or(superblock.inode_next);
+            self.store.write_superblock(&superblock)?;
+        }
+
+        self.store.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::nodes::FileNode;
+    use crate::fs::tests::{populated, ROOT};
+
+    fn file_named(store: &dyn NodeStore, name: &str) -> FileNode {
+        let id = store
+            .load_name_set(name.as_ref())
+            .into_iter()
+            .next()
+            .unwrap();
+        match store.load_node(&store.load_name_node(&id).unwrap().link) {
+            Some(INode::File(f)) => f,
+            _ => panic!("{name} isn't a file"),
+        }
+    }
+
+    #[test]
+    fn a_consistent_store_is_clean() {
+        let fs = populated();
+        let report = fsck(&*fs.store, true).unwrap();
+        assert_eq!((report.problems, report.repaired), (0, 0));
+    }
+
+    #[test]
+    fn names_of_files_that_are_gone_are_dropped() {
+        let fs = populated();
+        let store = &*fs.store;
+        let notes = file_named(store, "notes");
+        store.remove_node(&INode::File(notes)).unwrap();
+
+        let report = fsck(store, false).unwrap();
+        assert!(report.problems > 0);
+        assert_eq!(report.repaired, 0);
+        let report = fsck(store, true).unwrap();
+        assert_eq!(report.repaired, report.problems);
+
+        assert!(store.load_name_set("notes".as_ref()).is_empty());
+        let Some(INode::Tag(root)) = store.node_of(ROOT).and_then(|n| store.load_node(&n)) else {
+            panic!("there's no root");
+        };
+        assert!(root
+            .dir_links
+            .iter()
+            .all(|id| store.load_name_node(id).is_none_or(|n| n.name != "notes")));
+        assert_eq!(fsck(store, false).unwrap().problems, 0);
+    }
+
+    #[test]
+    fn files_go_by_the_hash_of_their_content() {
+        let fs = populated();
+        let store = &*fs.store;
+        let song = file_named(store, "song");
+        store.write_blob(&song.key(), 0, b"do re").unwrap();
+
+        let report = fsck(store, true).unwrap();
+        assert_eq!((report.problems, report.repaired), (1, 1));
+        let renamed = file_named(store, "song");
+        assert_eq!(renamed.file_attr.inode, song.file_attr.inode);
+        assert_ne!(renamed.key(), song.key());
+        assert_eq!(store.read_blob(&renamed.key(), 0, 4096).unwrap(), b"do re");
+        assert!(store.blob_size(&song.key()).is_none());
+        assert_eq!(fsck(store, false).unwrap().problems, 0);
+    }
+}
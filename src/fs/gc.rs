@@ -0,0 +1,220 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use log::debug;
+
+use crate::fs::nodes::{INode, Node};
+use crate::fs::store::NodeStore;
+
+/// How much a collection got rid of.
+#[derive(Default)]
+pub struct Collected {
+    pub nodes: usize,
+    pub names: usize,
+    pub blobs: usize,
+    pub inos: usize,
+    /// Tags that aren't named in any tag, which are kept until fsck puts them back
+    pub lost_tags: usize,
+}
+
+/// Removes whatever can't be reached from the root tag: nodes, the names in tags that are gone,
+/// content without a file and inode numbers that lead nowhere. Tags that aren't named in any tag
+/// at all are lost rather than garbage, they're kept along with what's in them and counted for
+/// fsck to put back in the root. Files that `is_open` says are still open are kept, they go away once they're released.
+/// The store has to be up to date, i.e. a mounted filesystem has to have written everything
+/// back.
+pub fn collect(store: &dyn NodeStore, is_open: impl Fn(u64) -> bool) -> io::Result<Collected> {
+    let root = store
+        .read_superblock()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there's no store to collect"))?
+        .root;
+    let mut collected = Collected::default();
+
+    let mut named = BTreeSet::new();
+    for id in store.list_tags() {
+        if let Some(INode::Tag(tag_node)) = store.load_node(&Node::Tag(id)) {
+            for id in &tag_node.dir_links {
+                if let Some(name_node) = store.load_name_node(id) {
+                    named.insert(name_node.link);
+                }
+            }
+        }
+    }
+
+    let mut pending: Vec<Node> = store
+        .list_tags()
+        .into_iter()
+        .map(Node::Tag)
+        .filter(|node| *node != Node::Tag(root) && !named.contains(node))
+        .collect();
+    collected.lost_tags = pending.len();
+    pending.push(Node::Tag(root));
+
+    // Mark everything named in a tag that can be reached, "." and ".." aren't stored as names
+    let mut reachable = BTreeSet::new();
+    let mut names = BTreeSet::new();
+    while let Some(node) = pending.pop() {
+        if !reachable.insert(node.clone()) {
+            continue;
+        }
+        let Some(INode::Tag(tag_node)) = store.load_node(&node) else {
+            continue;
+        };
+        for id in &tag_node.dir_links {
+            if let Some(name_node) = store.load_name_node(id) {
+                names.insert(*id);
+                pending.push(name_node.link);
+            }
+        }
+    }
+
+    let nodes = store
+        .list_tags()
+        .into_iter()
+        .map(Node::Tag)
+        .chain(store.list_symlinks().into_iter().map(Node::Symlink))
+        .chain(store.list_files().into_iter().map(Node::File));
+    let mut blobs = BTreeSet::new();
+    for node in nodes {
+        let Some(mut inode) = store.load_node(&node) else {
+            continue;
+        };
+        // Not stored where it belongs, that's for fsck to sort out
+        if inode.to_node() != node {
+            continue;
+        }
+        if !reachable.contains(&node) && !is_open(inode.attr().inode) {
+            debug!("\tcollect | {node}");
+            store.remove_node(&inode)?;
+            collected.nodes += 1;
+            continue;
+        }
+
+        // Names in tags that are gone don't count anymore
+        let count = inode.back_links().len();
+        inode.back_links_mut().retain(|id| names.contains(id));
+        if inode.back_links().len() != count {
+            if !matches!(inode, INode::Tag(_)) {
+                inode.attr_mut().hardlinks = inode.back_links().len() as u32;
+            }
+            store.store_node(&inode)?;
+        }
+        if let INode::File(f) = inode {
+            blobs.insert(f.key());
+        }
+    }
+
+    for id in store.list_name_nodes() {
+        if !names.contains(&id) {
+            store.remove_name_node(&id)?;
+            collected.names += 1;
+        }
+    }
+    for name in store.list_names() {
+        let ids = store.load_name_set(&name);
+        let kept = ids
+            .iter()
+            .filter(|id| names.contains(id))
+            .copied()
+            .collect();
+        if kept != ids {
+            store.store_name_set(&name, &kept)?;
+        }
+    }
+
+    for key in store.list_blobs() {
+        if !blobs.contains(&key) {
+            store.remove_blob(&key)?;
+            collected.blobs += 1;
+        }
+    }
+
+    // An inode number has to lead to a node that still goes by it
+    for ino in store.list_inos() {
+        let inode = store.node_of(ino).and_then(|node| store.load_node(&node));
+        if inode.is_none_or(|inode| inode.attr().inode != ino) {
+            store.forget_ino(ino)?;
+            collected.inos += 1;
+        }
+    }
+
+    store.commit()?;
+
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fsck::fsck;
+    use crate::fs::tests::{populated, ROOT};
+
+    #[test]
+    fn nothing_is_collected_from_a_consistent_store() {
+        let fs = populated();
+        let collected = collect(&*fs.store, |_| false).unwrap();
+        assert_eq!(
+            (
+                collected.nodes,
+                collected.names,
+                collected.blobs,
+                collected.inos
+            ),
+            (0, 0, 0, 0)
+        );
+    }
+
+    // Forgets about a name in the root, as if it had been removed halfway
+    fn drop_from_root(store: &dyn NodeStore, name: &str) {
+        let Some(INode::Tag(mut root)) = store.node_of(ROOT).and_then(|n| store.load_node(&n))
+        else {
+            panic!("there's no root");
+        };
+        let id = root
+            .dir_links
+            .iter()
+            .copied()
+            .find(|id| store.load_name_node(id).is_some_and(|n| n.name == name))
+            .unwrap();
+        root.dir_links.remove(&id);
+        store.store_node(&INode::Tag(root)).unwrap();
+    }
+
+    #[test]
+    fn files_without_a_name_in_a_tag_are_collected() {
+        let fs = populated();
+        let store = &*fs.store;
+        drop_from_root(store, "notes");
+
+        let collected = collect(store, |_| false).unwrap();
+        assert_eq!(
+            (collected.nodes, collected.names, collected.blobs),
+            (1, 1, 1)
+        );
+        assert_eq!(store.list_files().len(), 1);
+        assert_eq!(store.list_inos().len(), 4);
+
+        let collected = collect(store, |_| false).unwrap();
+        assert_eq!(collected.nodes + collected.names, 0);
+    }
+
+    #[test]
+    fn lost_tags_are_left_for_fsck_to_put_back() {
+        let fs = populated();
+        let store = &*fs.store;
+        drop_from_root(store, "music");
+
+        // Only the name that isn't in any tag goes, the tag keeps what's in it
+        let collected = collect(store, |_| false).unwrap();
+        assert_eq!((collected.nodes, collected.names), (0, 1));
+        assert_eq!(collected.lost_tags, 1);
+        assert_eq!(store.list_tags().len(), 2);
+        assert_eq!(store.list_symlinks().len(), 1);
+
+        let report = fsck(store, true).unwrap();
+        assert_eq!(report.repaired, report.problems);
+        let collected = collect(store, |_| false).unwrap();
+        assert_eq!(collected.nodes + collected.names + collected.lost_tags, 0);
+        assert_eq!(fsck(store, false).unwrap().problems, 0);
+    }
+}
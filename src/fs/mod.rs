@@ -3,54 +3,89 @@ use fuser::{
     ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
     ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use libc::{c_int, EISDIR, ENOENT, ENOSYS};
-use log::debug;
+use libc::{
+    c_int, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ELOOP, ENODATA, ENOENT, ENOSYS, ENOTDIR, ENOTEMPTY,
+    ENOTSUP, EPERM, ERANGE, EROFS,
+};
+use log::{debug, error, warn};
 use sha3::{Digest, Sha3_256};
-use std::cmp::min;
-use std::collections::BTreeSet;
-use std::ffi::OsStr;
-use std::fs::{create_dir_all, File, OpenOptions};
-use std::os::unix::fs::FileExt;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
-use crate::fs::defs::{rewrite_symlink, InodeAttributes, BLOCK_SIZE};
+use crate::fs::defs::{InodeAttributes, BLOCK_SIZE};
 
-use self::defs::{time_now, FileKind, TTL};
-use self::nodes::{FileNode, INode, NameNode, Node, TagNode};
+use self::cache::{CacheKey, Cached, NodeCache};
+use self::defs::{
+    time_from_system_time, time_now, FileKey, FileKind, Superblock, FORMAT_VERSION,
+    NAME_SUFFIX_SEPARATOR, TAGS_XATTR, TTL,
+};
+use self::nodes::{FileNode, INode, NameNode, Node, SymlinkNode, TagNode};
+use self::store::NodeStore;
+use self::views::Views;
 
+mod cache;
 mod defs;
+pub mod fsck;
+pub mod gc;
 mod nodes;
+pub mod store;
+mod views;
 
 pub struct TagFS {
     hasher: Sha3_256,
-    data_dir: PathBuf,
+    store: Box<dyn NodeStore>,
+    root: Uuid,
     inode_cur: u64,
     filehandle_cur: u64,
+    // Files written to since their hash was last calculated
+    dirty_files: BTreeSet<u64>,
+    // How many times each file is open, this doesn't outlive the mount so it's never stored
+    open_handles: HashMap<u64, u32>,
+    views: Views,
+    // Nothing is ever written to the store, whatever would change it fails with EROFS
+    read_only: bool,
+    // Nested tags intersect instead of following the names stored in them
+    faceted: bool,
+    // Nodes that were read or written recently, the latter are written back later on
+    cache: RefCell<NodeCache>,
+    // How often to get rid of whatever can't be reached anymore, if at all
+    gc_interval: Option<Duration>,
+    gc_last: Cell<Instant>,
+    // The first write to the store that failed since the last flush, which gets to report it
+    failed_write: RefCell<Option<io::Error>>,
 }
 
 impl TagFS {
-    pub fn new() -> Self {
-        let base_path = PathBuf::from("/tmp/tagfs");
-        for subdir in [
-            "inodes",
-            "namenodes",
-            "namenodes_id",
-            "filenodes",
-            "tagnodes",
-        ] {
-            create_dir_all(base_path.join(subdir)).unwrap();
-        }
-
-        let fs = Self {
+    pub fn new(
+        store: Box<dyn NodeStore>,
+        read_only: bool,
+        faceted: bool,
+        cache_size: NonZeroUsize,
+        gc_interval: Option<Duration>,
+    ) -> Self {
+        Self {
             hasher: Sha3_256::new(),
-            data_dir: base_path,
+            store,
+            root: Uuid::nil(),
             inode_cur: 1,
             filehandle_cur: 1,
-        };
-
-        fs
+            dirty_files: BTreeSet::new(),
+            open_handles: HashMap::new(),
+            views: Views::default(),
+            read_only,
+            faceted,
+            cache: RefCell::new(NodeCache::new(cache_size)),
+            gc_interval,
+            gc_last: Cell::new(Instant::now()),
+            failed_write: RefCell::new(None),
+        }
     }
 
     fn get_inode_cur(inode_cur: &mut u64) -> u64 {
@@ -69,182 +104,923 @@ impl TagFS {
         &mut self,
         inode_kind: FileKind,
         attr: Option<InodeAttributes>,
-    ) -> INode {
+    ) -> Result<INode, c_int> {
         debug!("\tallocate_next_inode | {inode_kind:?}");
 
-        match inode_kind {
-            FileKind::File => INode::File(FileNode::new(
-                &mut self.hasher,
+        let inode = match inode_kind {
+            FileKind::File => {
+                let file_node = FileNode::new(
+                    &mut self.hasher,
+                    TagFS::get_inode_cur(&mut self.inode_cur),
+                    attr,
+                );
+                // Every file starts out with empty content
+                self.store
+                    .create_blob(&file_node.key())
+                    .map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+                INode::File(file_node)
+            }
+            FileKind::Directory => INode::Tag(TagNode::new(
                 TagFS::get_inode_cur(&mut self.inode_cur),
                 attr,
             )),
-            FileKind::Directory => INode::Tag(TagNode::new(
+            // The target is filled in by the caller
+            FileKind::Symlink => INode::Symlink(SymlinkNode::new(
                 TagFS::get_inode_cur(&mut self.inode_cur),
+                PathBuf::new(),
                 attr,
             )),
-            FileKind::Symlink => unimplemented!(),
-        }
+        };
+        // The next inode number has to survive a remount, otherwise we'd hand out numbers that
+        // are already taken
+        self.write_superblock();
+
+        Ok(inode)
+    }
+
+    fn write_superblock(&self) {
+        debug!("\twrite_superblock | next inode: {}", self.inode_cur);
+
+        let superblock = Superblock {
+            version: FORMAT_VERSION,
+            root: self.root,
+            inode_next: self.inode_cur,
+        };
+        self.note_failure(self.store.write_superblock(&superblock));
     }
 
     fn get_inode(&self, ino: u64) -> Result<INode, c_int> {
         debug!("\tget_inode | {ino}");
 
-        if let Ok(path) = self
-            .data_dir
-            .join("inodes")
-            .join(ino.to_string())
-            .read_link()
-        {
-            if let Ok(file) = File::open(&path) {
-                let parent = path.parent().unwrap();
-                if parent.ends_with("tagnodes") {
-                    return Ok(INode::Tag(bincode::deserialize_from(file).unwrap()));
-                } else if parent.ends_with("filenodes") {
-                    return Ok(INode::File(bincode::deserialize_from(file).unwrap()));
-                }
-            }
+        // The cache has to be let go of before get_node gets to it
+        let cached = self.cache.borrow().node_of(ino);
+        if let Some(node) = cached {
+            return self.get_node(&node);
         }
 
-        Err(libc::ENOENT)
-    }
-
-    fn get_node_from_inode(&self, ino: u64) -> Result<Node, c_int> {
-        match self.get_inode(ino) {
-            Ok(INode::File(f)) => Ok(Node::File(f.hash)),
-            Ok(INode::Tag(t)) => Ok(Node::Tag(t.id)),
-            Err(_) => Err(libc::ENOENT),
-        }
+        let node = self.store.node_of(ino).ok_or(ENOENT)?;
+        self.get_node(&node)
     }
 
     fn get_name_node(&self, id: &Uuid) -> Result<NameNode, c_int> {
         debug!("\tget_name_node | {id}");
-        let path = self.data_dir.join("namenodes_id").join(id.to_string());
-        if let Ok(file) = File::open(&path) {
-            Ok(bincode::deserialize_from(file).unwrap())
-        } else {
-            Err(libc::ENOENT)
+
+        let key = CacheKey::Name(*id);
+        if let Some(Cached::Name(name_node)) = self.cache.borrow_mut().get(&key) {
+            return Ok(name_node);
         }
+
+        let name_node = self.store.load_name_node(id).ok_or(ENOENT)?;
+        self.cache_insert(key, Cached::Name(name_node.clone()), false);
+
+        Ok(name_node)
     }
 
     fn get_node(&self, link_node: &Node) -> Result<INode, c_int> {
         debug!("\tget_node | {link_node}");
 
-        match link_node {
-            Node::File(hash) => {
-                let path = self.data_dir.join("filenodes").join(hash.code.to_string());
-                if let Ok(file) = File::open(&path) {
-                    Ok(INode::File(bincode::deserialize_from(file).unwrap()))
-                } else {
-                    Err(libc::ENOENT)
-                }
-            }
-            Node::Tag(id) => {
-                let path = self.data_dir.join("tagnodes").join(id.to_string());
-                if let Ok(file) = File::open(&path) {
-                    Ok(INode::Tag(bincode::deserialize_from(file).unwrap()))
-                } else {
-                    Err(libc::ENOENT)
-                }
-            }
+        let key = CacheKey::Node(link_node.clone());
+        if let Some(Cached::Node(inode)) = self.cache.borrow_mut().get(&key) {
+            return Ok(inode);
         }
+
+        let inode = self.store.load_node(link_node).ok_or(ENOENT)?;
+        self.cache_insert(key, Cached::Node(inode.clone()), false);
+
+        Ok(inode)
     }
 
     pub fn insert_inode(&mut self, node: &INode) {
         match node {
             INode::Tag(f) => self.write_tag_node(f),
             INode::File(t) => self.write_file_node(t),
+            INode::Symlink(l) => self.write_symlink_node(l),
         }
     }
 
     fn write_file_node(&self, inode: &FileNode) {
         debug!("\twrite_file_node | {inode}");
-
-        let path = Path::new(&self.data_dir)
-            .join("filenodes")
-            .join(inode.hash.code.clone());
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, inode).unwrap();
-
-        let symlink_path = Path::new(&self.data_dir)
-            .join("inodes")
-            .join(inode.file_attr.inode.to_string());
-
-        rewrite_symlink(path, symlink_path);
+        self.cache_insert(
+            CacheKey::Node(Node::File(inode.key())),
+            Cached::Node(INode::File(inode.clone())),
+            true,
+        );
     }
 
     fn write_tag_node(&self, inode: &TagNode) {
         debug!("\twrite_tag_node | {inode}");
+        self.cache_insert(
+            CacheKey::Node(Node::Tag(inode.id)),
+            Cached::Node(INode::Tag(inode.clone())),
+            true,
+        );
+    }
 
-        let path = Path::new(&self.data_dir)
-            .join("tagnodes")
-            .join(inode.id.to_string());
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, inode).unwrap();
+    fn write_symlink_node(&self, inode: &SymlinkNode) {
+        debug!("\twrite_symlink_node | {inode}");
+        self.cache_insert(
+            CacheKey::Node(Node::Symlink(inode.id)),
+            Cached::Node(INode::Symlink(inode.clone())),
+            true,
+        );
+    }
 
-        let symlink_path = Path::new(&self.data_dir)
-            .join("inodes")
-            .join(inode.dir_attr.inode.to_string());
+    // Ids of the name nodes going by a name, in any tag
+    fn get_name_set(&self, name: &OsStr) -> BTreeSet<Uuid> {
+        let key = CacheKey::NameSet(name.to_os_string());
+        if let Some(Cached::NameSet(ids)) = self.cache.borrow_mut().get(&key) {
+            return ids;
+        }
+
+        let ids = self.store.load_name_set(name);
+        self.cache_insert(key, Cached::NameSet(ids.clone()), false);
 
-        rewrite_symlink(path, symlink_path);
+        ids
     }
 
     pub fn insert_name_node(&mut self, name_node: &NameNode) {
         debug!("\tinsert_name_node | {name_node}");
 
         // BTreeSet by name
-        let path = Path::new(&self.data_dir)
-            .join("namenodes")
-            .join(name_node.name.clone());
+        let mut ids = self.get_name_set(&name_node.name);
+        ids.insert(name_node.id);
+        self.cache_insert(
+            CacheKey::NameSet(name_node.name.clone()),
+            Cached::NameSet(ids),
+            true,
+        );
+
+        // By UUID
+        self.cache_insert(
+            CacheKey::Name(name_node.id),
+            Cached::Name(name_node.clone()),
+            true,
+        );
+    }
 
-        let mut b = BTreeSet::new();
+    pub fn remove_name_node(&mut self, name_node: &NameNode) {
+        debug!("\tremove_name_node | {name_node}");
 
-        if path.exists() {
-            let file = OpenOptions::new().read(true).open(&path).unwrap();
-            b = bincode::deserialize_from(file).unwrap();
+        let mut ids = self.get_name_set(&name_node.name);
+        ids.remove(&name_node.id);
+        if ids.is_empty() {
+            self.cache
+                .borrow_mut()
+                .remove(&CacheKey::NameSet(name_node.name.clone()));
+            self.note_failure(self.store.store_name_set(&name_node.name, &ids));
+        } else {
+            self.cache_insert(
+                CacheKey::NameSet(name_node.name.clone()),
+                Cached::NameSet(ids),
+                true,
+            );
         }
 
-        b.insert(name_node.id);
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, &b).unwrap();
+        self.cache
+            .borrow_mut()
+            .remove(&CacheKey::Name(name_node.id));
+        self.note_failure(self.store.remove_name_node(&name_node.id));
+    }
 
-        // By UUID
-        let path = Path::new(&self.data_dir)
-            .join("namenodes_id")
-            .join(name_node.id.to_string());
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, name_node).unwrap();
+    fn cache_insert(&self, key: CacheKey, value: Cached, dirty: bool) {
+        let evicted = self.cache.borrow_mut().insert(key, value, dirty);
+        if let Some((key, value)) = evicted {
+            self.note_failure(self.store_cached(&key, value));
+        }
     }
 
-    // Service functions
+    fn store_cached(&self, key: &CacheKey, value: Cached) -> io::Result<()> {
+        match (key, value) {
+            (_, Cached::Node(inode)) => self.store.store_node(&inode),
+            (_, Cached::Name(name_node)) => self.store.store_name_node(&name_node),
+            (CacheKey::NameSet(name), Cached::NameSet(ids)) => {
+                self.store.store_name_set(name, &ids)
+            }
+            (key, _) => {
+                error!("store_cached | {key:?} holds the wrong kind of value");
+                Ok(())
+            }
+        }
+    }
+
+    // Most writes to the store happen where there's nobody to tell that they failed, the next
+    // flush reports it instead
+    fn note_failure(&self, result: io::Result<()>) {
+        if let Err(e) = result {
+            error!("store | {e}");
+            self.failed_write.borrow_mut().get_or_insert(e);
+        }
+    }
+
+    /// Writes every node that has changed since it was cached back to the store and commits,
+    /// failing if anything written since the last time didn't make it.
+    fn flush_cache(&self) -> io::Result<()> {
+        debug!("\tflush_cache");
+        if self.read_only {
+            return Ok(());
+        }
+
+        let dirty = self.cache.borrow_mut().take_dirty();
+        for (key, value) in dirty {
+            self.note_failure(self.store_cached(&key, value));
+        }
+        if let Some(e) = self.failed_write.borrow_mut().take() {
+            return Err(e);
+        }
+        self.store.commit()
+    }
+
+    // Flushes on behalf of a caller that has to be told whether it worked, which is also when
+    // garbage is collected
+    fn sync(&self) -> Result<(), c_int> {
+        self.flush_cache().map_err(|e| {
+            error!("sync | {e}");
+            EIO
+        })?;
+        self.collect_garbage_if_due();
+        Ok(())
+    }
+
+    // Runs the garbage collector once it's due, the cache has to have been flushed just before
+    fn collect_garbage_if_due(&self) {
+        if self.read_only {
+            return;
+        }
+        match self.gc_interval {
+            Some(interval) if self.gc_last.get().elapsed() >= interval => (),
+            _ => return,
+        }
+        self.gc_last.set(Instant::now());
+
+        let open_handles = &self.open_handles;
+        match gc::collect(self.store.as_ref(), |ino| open_handles.contains_key(&ino)) {
+            Ok(collected) => {
+                debug!(
+                    "\tcollect_garbage | {} nodes, {} names, {} blobs, {} inode numbers",
+                    collected.nodes, collected.names, collected.blobs, collected.inos
+                );
+                if collected.lost_tags > 0 {
+                    warn!(
+                        "collect_garbage | {} tags without names are kept, fsck --repair puts them back",
+                        collected.lost_tags
+                    );
+                }
+            }
+            Err(e) => error!("collect_garbage | {e}"),
+        }
+        // Whatever it changed might be cached, there's nothing dirty in there to lose
+        self.cache.borrow_mut().clear();
+    }
+
+    // Drops the file with its content entirely, only to be called once nothing refers to it
+    fn remove_file_node(&mut self, inode: &FileNode) {
+        debug!("\tremove_file_node | {inode}");
+
+        self.dirty_files.remove(&inode.file_attr.inode);
+        self.cache
+            .borrow_mut()
+            .remove(&CacheKey::Node(Node::File(inode.key())));
+        self.note_failure(self.store.remove_node(&INode::File(inode.clone())));
+        self.note_failure(self.store.remove_blob(&inode.key()));
+    }
+
+    fn remove_tag_node(&mut self, inode: &TagNode) {
+        debug!("\tremove_tag_node | {inode}");
+
+        self.cache
+            .borrow_mut()
+            .remove(&CacheKey::Node(Node::Tag(inode.id)));
+        self.note_failure(self.store.remove_node(&INode::Tag(inode.clone())));
+    }
+
+    fn remove_symlink_node(&mut self, inode: &SymlinkNode) {
+        debug!("\tremove_symlink_node | {inode}");
+
+        self.cache
+            .borrow_mut()
+            .remove(&CacheKey::Node(Node::Symlink(inode.id)));
+        self.note_failure(self.store.remove_node(&INode::Symlink(inode.clone())));
+    }
+
+    // Nodes that haven't been written back yet are only in the cache
+    fn list_tag_nodes(&self) -> Vec<TagNode> {
+        let mut ids: BTreeSet<Uuid> = self.store.list_tags().into_iter().collect();
+        ids.extend(self.cache.borrow().keys().filter_map(|key| match key {
+            CacheKey::Node(Node::Tag(id)) => Some(*id),
+            _ => None,
+        }));
+
+        ids.into_iter()
+            .filter_map(|id| match self.get_node(&Node::Tag(id)) {
+                Ok(INode::Tag(t)) => Some(t),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_file_nodes(&self) -> Vec<FileNode> {
+        let mut keys: BTreeSet<FileKey> = self.store.list_files().into_iter().collect();
+        keys.extend(self.cache.borrow().keys().filter_map(|key| match key {
+            CacheKey::Node(Node::File(key)) => Some(key.clone()),
+            _ => None,
+        }));
+
+        keys.into_iter()
+            .filter_map(|key| match self.get_node(&Node::File(key)) {
+                Ok(INode::File(f)) => Some(f),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Tags are named by the name nodes pointing at them, the first one is as good as any
+    fn tag_name(&self, tag_node: &TagNode) -> Option<OsString> {
+        self.tag_name_node(tag_node).map(|n| n.name)
+    }
+
+    fn tag_name_node(&self, tag_node: &TagNode) -> Option<NameNode> {
+        tag_node
+            .back_links
+            .iter()
+            .find_map(|id| self.get_name_node(id).ok())
+    }
+
+    fn find_tag<'a>(&self, tag_nodes: &'a [TagNode], name: &OsStr) -> Option<&'a TagNode> {
+        tag_nodes
+            .iter()
+            .find(|t| t.id != self.root && self.tag_name(t).as_deref() == Some(name))
+    }
+
+    // Every tag (except for the root) that has a name for the node
+    fn node_tags(&self, inode: &INode) -> Vec<TagNode> {
+        let back_links = inode.back_links();
+
+        self.list_tag_nodes()
+            .into_iter()
+            .filter(|t| t.id != self.root && back_links.iter().any(|id| t.dir_links.contains(id)))
+            .collect()
+    }
+
+    fn get_tags_xattr(&self, inode: &INode) -> Vec<u8> {
+        let names: Vec<Vec<u8>> = self
+            .node_tags(inode)
+            .iter()
+            .filter_map(|t| self.tag_name(t))
+            .map(|name| name.as_bytes().to_vec())
+            .collect();
+
+        names.join(&b'\n')
+    }
+
+    // Tags a file with exactly the given tags, adding and removing names as needed
+    fn set_tags_xattr(&mut self, ino: u64, value: &[u8]) -> Result<(), c_int> {
+        self.writable()?;
+        let inode = self.get_inode(ino)?;
+        if let INode::Tag(_) = inode {
+            return Err(EPERM);
+        }
+
+        let tag_nodes = self.list_tag_nodes();
+        let mut wanted = BTreeSet::new();
+        for name in value.split(|c| *c == b'\n').filter(|name| !name.is_empty()) {
+            let tag_node = self
+                .find_tag(&tag_nodes, OsStr::from_bytes(name))
+                .ok_or(ENOENT)?;
+            wanted.insert(tag_node.dir_attr.inode);
+        }
+
+        let current: BTreeSet<u64> = self
+            .node_tags(&inode)
+            .iter()
+            .map(|t| t.dir_attr.inode)
+            .collect();
+
+        // New names reuse whatever the file is already called
+        let name = match (inode.back_links().first(), inode.to_node()) {
+            (Some(id), _) => self.get_name_node(id)?.name,
+            (None, Node::File(key)) => OsString::from(key.to_string()),
+            (None, Node::Tag(id) | Node::Symlink(id)) => OsString::from(id.to_string()),
+        };
+
+        // A file that is left without any tags stays reachable from the root
+        let mut to_add: Vec<u64> = wanted.difference(&current).copied().collect();
+        if wanted.is_empty() {
+            let root_ino = self.get_node(&Node::Tag(self.root))?.attr().inode;
+            let in_root = self
+                .get_tag_node(root_ino)?
+                .dir_links
+                .iter()
+                .any(|id| inode.back_links().contains(id));
+            if !in_root {
+                to_add.push(root_ino);
+            }
+        }
+
+        for tag_ino in to_add {
+            let mut inode = self.get_inode(ino)?;
+            let mut tag_node = self.get_tag_node(tag_ino)?;
+
+            let name_node = NameNode::new(name.clone(), inode.to_node());
+            tag_node.add_file(&name_node);
+            tag_node.dir_attr.last_modified = time_now();
+            tag_node.dir_attr.last_metadata_changed = time_now();
+            self.insert_name_node(&name_node);
+            self.write_tag_node(&tag_node);
+
+            inode.add_back_link(&name_node);
+            inode.attr_mut().hardlinks += 1;
+            inode.attr_mut().last_metadata_changed = time_now();
+            self.insert_inode(&inode);
+        }
 
-    pub fn search_name(&self, tag_node: &TagNode, os_name: &OsStr) -> Option<INode> {
+        for tag_ino in current.difference(&wanted) {
+            let tag_node = self.get_tag_node(*tag_ino)?;
+            for id in &tag_node.dir_links {
+                let inode = self.get_inode(ino)?;
+                if inode.back_links().contains(id) {
+                    let name_node = self.get_name_node(id)?;
+                    self.unlink_node(*tag_ino, &name_node, inode);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_tag_node(&self, ino: u64) -> Result<TagNode, c_int> {
+        match self.get_inode(ino)? {
+            INode::Tag(t) => Ok(t),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    // Removes a single name of a file or a symlink from a tag
+    fn unlink_node(&mut self, parent: u64, name_node: &NameNode, mut inode: INode) {
+        if let Ok(mut parent_node) = self.get_tag_node(parent) {
+            parent_node.remove_file(name_node);
+            parent_node.dir_attr.last_modified = time_now();
+            parent_node.dir_attr.last_metadata_changed = time_now();
+            self.write_tag_node(&parent_node);
+        }
+        self.remove_name_node(name_node);
+
+        inode.remove_back_link(name_node);
+        let attrs = inode.attr_mut();
+        attrs.hardlinks = attrs.hardlinks.saturating_sub(1);
+        attrs.last_metadata_changed = time_now();
+        self.insert_inode(&inode);
+        self.release_if_orphaned(inode.attr().inode);
+    }
+
+    // Removes an empty tag altogether
+    fn remove_tag(&mut self, name_node: NameNode, tag_node: TagNode) -> Result<(), c_int> {
+        if tag_node.id == self.root {
+            return Err(EBUSY);
+        }
+
+        let mut own_links = Vec::new();
         for id in &tag_node.dir_links {
+            if let Ok(n) = self.get_name_node(id) {
+                if n.name != "." && n.name != ".." {
+                    return Err(ENOTEMPTY);
+                }
+                own_links.push(n);
+            }
+        }
+
+        // A tag might be named in several other tags, removing it drops every one of those names
+        // so that none of them is left dangling
+        let mut names: Vec<NameNode> = tag_node
+            .back_links
+            .iter()
+            .filter_map(|id| self.get_name_node(id).ok())
+            .collect();
+        if !tag_node.back_links.contains(&name_node.id) {
+            names.push(name_node);
+        }
+
+        for mut other in self.list_tag_nodes() {
+            let mut changed = false;
+            for n in &names {
+                if other.dir_links.contains(&n.id) {
+                    other.remove_file(n);
+                    changed = true;
+                }
+            }
+
+            if changed {
+                other.dir_attr.last_modified = time_now();
+                other.dir_attr.last_metadata_changed = time_now();
+                self.write_tag_node(&other);
+            }
+        }
+
+        for n in names.iter().chain(own_links.iter()) {
+            self.remove_name_node(n);
+        }
+        self.remove_tag_node(&tag_node);
+
+        Ok(())
+    }
+
+    // Renames a name node and moves it over to another tag, the node it links to stays the same
+    fn move_name_node(
+        &mut self,
+        parent: u64,
+        newparent: u64,
+        name_node: &NameNode,
+        newname: &OsStr,
+    ) {
+        let mut moved = name_node.clone();
+        moved.name = newname.to_os_string();
+
+        if let Ok(mut parent_node) = self.get_tag_node(parent) {
+            parent_node.remove_file(name_node);
+            if parent == newparent {
+                parent_node.add_file(&moved);
+            }
+            parent_node.dir_attr.last_modified = time_now();
+            parent_node.dir_attr.last_metadata_changed = time_now();
+            self.write_tag_node(&parent_node);
+        }
+        if parent != newparent {
+            if let Ok(mut newparent_node) = self.get_tag_node(newparent) {
+                newparent_node.add_file(&moved);
+                newparent_node.dir_attr.last_modified = time_now();
+                newparent_node.dir_attr.last_metadata_changed = time_now();
+                self.write_tag_node(&newparent_node);
+            }
+        }
+
+        // The index by name has to forget the old name
+        self.remove_name_node(name_node);
+        self.insert_name_node(&moved);
+    }
+
+    // A file without any names is only kept around while somebody still has it open
+    fn release_if_orphaned(&mut self, ino: u64) {
+        if self.read_only {
+            return;
+        }
+        match self.get_inode(ino) {
+            Ok(INode::File(f))
+                if f.file_attr.hardlinks == 0 && !self.open_handles.contains_key(&ino) =>
+            {
+                self.remove_file_node(&f)
+            }
+            Ok(INode::Symlink(l)) if l.link_attr.hardlinks == 0 => self.remove_symlink_node(&l),
+            _ => (),
+        }
+    }
+
+    // Files report the size of their content, not whatever was last written into their metadata
+    fn inode_attr(&self, inode: INode) -> fuser::FileAttr {
+        let size = match &inode {
+            INode::File(f) => Some(self.content_size(&f.key())),
+            _ => None,
+        };
+
+        let mut attrs = inode.into_attr();
+        if let Some(size) = size {
+            attrs.size = size;
+        }
+        attrs.into()
+    }
+
+    // File content is stored under the same key as its FileNode, the latter only holds the
+    // metadata
+    fn content_size(&self, key: &FileKey) -> u64 {
+        self.store.blob_size(key).unwrap_or(0)
+    }
+
+    /// Recalculates the hash of a file after it has been written to, moving its `filenodes/` entry
+    /// and content to the new hash and repointing every name that links to it.
+    fn rehash_file_node(&mut self, ino: u64) -> Result<(), c_int> {
+        debug!("\trehash_file_node | {ino}");
+
+        if !self.dirty_files.remove(&ino) {
+            return Ok(());
+        }
+
+        let mut file_node = match self.get_inode(ino)? {
+            INode::File(f) => f,
+            INode::Tag(_) => return Err(EISDIR),
+            INode::Symlink(_) => return Err(EINVAL),
+        };
+        let old_key = file_node.key();
+        let old_node = INode::File(file_node.clone());
+
+        let mut content = self.store.blob_reader(&old_key).map_err(|_| ENOENT)?;
+        file_node
+            .calculate_hashes(&mut self.hasher, &mut content)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        if file_node.key() == old_key {
+            return Ok(());
+        }
+
+        self.store
+            .rename_blob(&old_key, &file_node.key())
+            .map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        self.cache
+            .borrow_mut()
+            .remove(&CacheKey::Node(Node::File(old_key)));
+        self.write_file_node(&file_node);
+        self.note_failure(self.store.remove_node(&old_node));
+
+        // Names keep pointing at the file by its key, so every one of them has to follow it
+        for id in &file_node.back_links {
+            if let Ok(mut name_node) = self.get_name_node(id) {
+                name_node.link = Node::File(file_node.key());
+                self.insert_name_node(&name_node);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whatever would change the store fails on a read-only mount
+    fn writable(&self) -> Result<(), c_int> {
+        match self.read_only {
+            true => Err(EROFS),
+            false => Ok(()),
+        }
+    }
+
+    /// Picks up an existing store where it was left off, an empty one gets a root tag.
+    fn open_store(&mut self) -> Result<(), c_int> {
+        let superblock = match self.store.read_superblock() {
+            Ok(superblock) => superblock,
+            Err(e) => {
+                error!("open_store | superblock is corrupted: {e}");
+                return Err(EIO);
+            }
+        };
+        let superblock = match superblock {
+            Some(superblock) => superblock,
+            // Don't clobber whatever else might be living in the directory
+            None if !self.store.is_empty() => {
+                error!("open_store | store has nodes but no superblock");
+                return Err(EINVAL);
+            }
+            None if self.read_only => {
+                error!("open_store | store is empty, there's no root to mount read-only");
+                return Err(EROFS);
+            }
+            None => {
+                if let Err(e) = store::format(&*self.store) {
+                    error!("open_store | can't create the root: {e}");
+                    return Err(EIO);
+                }
+                return self.open_store();
+            }
+        };
+        if superblock.version != FORMAT_VERSION {
+            error!(
+                "open_store | store has format version {}, expected {FORMAT_VERSION}",
+                superblock.version
+            );
+            return Err(EINVAL);
+        }
+        debug!("\t{superblock:?}");
+
+        self.root = superblock.root;
+        self.inode_cur = superblock.inode_next;
+        match self.get_node(&Node::Tag(self.root)) {
+            Ok(INode::Tag(_)) => Ok(()),
+            _ => {
+                error!("open_store | root tag {} is missing", self.root);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Creates a file, a tag or a symlink under a name in a tag, whichever `attrs` is for.
+    /// Symlinks are left for the caller to point somewhere.
+    fn create_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        attrs: InodeAttributes,
+    ) -> Result<INode, c_int> {
+        self.writable()?;
+        let mut parent_node = self.get_tag_node(parent)?;
+        parent_node.dir_attr.last_modified = time_now();
+        parent_node.dir_attr.last_metadata_changed = time_now();
+
+        let mut inode = self.allocate_next_inode(attrs.kind, Some(attrs))?;
+        if let INode::Tag(ref mut t) = inode {
+            t.add_file(&NameNode::new(".".into(), Node::Tag(t.id)));
+            t.add_file(&NameNode::new("..".into(), Node::Tag(parent_node.id)));
+        };
+
+        let name_node = NameNode::new(name.to_os_string(), inode.to_node());
+        parent_node.add_file(&name_node);
+        inode.add_back_link(&name_node);
+        self.insert_name_node(&name_node);
+        self.write_tag_node(&parent_node);
+        self.insert_inode(&inode);
+
+        Ok(inode)
+    }
+
+    fn create_symlink(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        attrs: InodeAttributes,
+    ) -> Result<INode, c_int> {
+        let mut inode = self.create_node(parent, name, attrs)?;
+        if let INode::Symlink(ref mut l) = inode {
+            l.target = link.to_path_buf();
+            // Callers size their buffer for readlink by this
+            l.link_attr.size = link.as_os_str().len() as u64;
+        }
+        self.insert_inode(&inode);
+
+        Ok(inode)
+    }
+
+    // Names aren't unique, so this only removes the first one that matches
+    fn unlink_name(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.writable()?;
+        let parent_node = self.get_tag_node(parent)?;
+        match self.search_name(&parent_node, name) {
+            Some((_, INode::Tag(_))) => Err(EISDIR),
+            Some((name_node, inode)) => {
+                self.unlink_node(parent, &name_node, inode);
+                Ok(())
+            }
+            None => Err(ENOENT),
+        }
+    }
+
+    fn rmdir_name(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.writable()?;
+        let parent_node = self.get_tag_node(parent)?;
+        match self.search_name(&parent_node, name) {
+            Some((name_node, INode::Tag(t))) => self.remove_tag(name_node, t),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn rename_name(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        self.writable()?;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let noreplace = flags & libc::RENAME_NOREPLACE != 0;
+        if (exchange && noreplace) || flags & !(libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE) != 0
+        {
+            return Err(EINVAL);
+        }
+
+        let parent_node = self.get_tag_node(parent)?;
+        let newparent_node = self.get_tag_node(newparent)?;
+        let (name_node, node) = self.search_name(&parent_node, name).ok_or(ENOENT)?;
+
+        // Names aren't unique, so whatever is already called newname would happily live next to
+        // the renamed node. We still replace the first match like any other filesystem would,
+        // editors depend on renaming over a file to save it.
+        let existing = self
+            .search_name(&newparent_node, newname)
+            .filter(|(n, _)| n.id != name_node.id);
+
+        if exchange {
+            let (existing_name, _) = existing.ok_or(ENOENT)?;
+            self.move_name_node(parent, newparent, &name_node, newname);
+            self.move_name_node(newparent, parent, &existing_name, name);
+            return Ok(());
+        }
+
+        if let Some((existing_name, existing_node)) = existing {
+            if noreplace {
+                return Err(EEXIST);
+            }
+
+            match (&node, existing_node) {
+                (INode::Tag(_), INode::Tag(t)) => self.remove_tag(existing_name, t)?,
+                (_, INode::Tag(_)) => return Err(EISDIR),
+                (INode::Tag(_), _) => return Err(ENOTDIR),
+                (_, inode) => self.unlink_node(newparent, &existing_name, inode),
+            }
+        }
+
+        self.move_name_node(parent, newparent, &name_node, newname);
+
+        Ok(())
+    }
+
+    // Just another name for the same file, which is how it ends up in several tags at once
+    fn link_name(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<INode, c_int> {
+        self.writable()?;
+        let mut inode = match self.get_inode(ino)? {
+            INode::Tag(_) => return Err(EPERM),
+            inode => inode,
+        };
+        let mut newparent_node = self.get_tag_node(newparent)?;
+
+        let name_node = NameNode::new(newname.to_os_string(), inode.to_node());
+        newparent_node.add_file(&name_node);
+        newparent_node.dir_attr.last_modified = time_now();
+        newparent_node.dir_attr.last_metadata_changed = time_now();
+        self.insert_name_node(&name_node);
+        self.write_tag_node(&newparent_node);
+
+        inode.add_back_link(&name_node);
+        inode.attr_mut().hardlinks += 1;
+        inode.attr_mut().last_metadata_changed = time_now();
+        self.insert_inode(&inode);
+
+        Ok(inode)
+    }
+
+    fn write_file(&mut self, ino: u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        self.writable()?;
+        let mut file_node = match self.get_inode(ino)? {
+            INode::File(f) => f,
+            INode::Tag(_) => return Err(EISDIR),
+            INode::Symlink(_) => return Err(EINVAL),
+        };
+
+        // The content stays under the old hash until the file is flushed, only then it gets
+        // rehashed and moved
+        let size = self
+            .store
+            .write_blob(&file_node.key(), offset, data)
+            .map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+
+        let attrs = &mut file_node.file_attr;
+        attrs.size = size;
+        attrs.last_modified = time_now();
+        attrs.last_metadata_changed = time_now();
+        self.write_file_node(&file_node);
+        self.dirty_files.insert(ino);
+
+        Ok(())
+    }
+
+    // Brings the hash of a file up to date and makes everything so far durable
+    fn flush_file(&mut self, ino: u64) -> Result<(), c_int> {
+        let rehashed = self.rehash_file_node(ino);
+        self.sync()?;
+        rehashed
+    }
+
+    fn open_file(&mut self, ino: u64, flags: i32) -> Result<u64, c_int> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            self.writable()?;
+        }
+        match self.get_inode(ino)? {
+            INode::File(_) => {
+                *self.open_handles.entry(ino).or_default() += 1;
+                Ok(self.get_filehandle_cur())
+            }
+            INode::Tag(_) => Err(EISDIR),
+            INode::Symlink(_) => Err(ELOOP),
+        }
+    }
+
+    // Whoever lets go of a file that lost all of its names last gets rid of it
+    fn release_file(&mut self, ino: u64) -> Result<(), c_int> {
+        let rehashed = self.rehash_file_node(ino);
+        if let Some(handles) = self.open_handles.get_mut(&ino) {
+            *handles -= 1;
+            if *handles == 0 {
+                self.open_handles.remove(&ino);
+            }
+        }
+        self.release_if_orphaned(ino);
+        self.sync()?;
+        rehashed
+    }
+
+    // Service functions
+
+    pub fn search_name(&self, tag_node: &TagNode, os_name: &OsStr) -> Option<(NameNode, INode)> {
+        for id in tag_node.named(os_name) {
             if let Ok(name_node) = self.get_name_node(id) {
-                if &name_node.name == os_name {
+                if let Ok(node) = self.get_node(&name_node.link) {
+                    return Some((name_node, node));
+                }
+            }
+        }
+
+        // Might be one of the duplicates readdir had to add a suffix to
+        let bytes = os_name.as_bytes();
+        let split = bytes.iter().rposition(|c| *c == NAME_SUFFIX_SEPARATOR)?;
+        let (base, suffix) = (OsStr::from_bytes(&bytes[..split]), &bytes[split + 1..]);
+        for id in tag_node.named(base) {
+            if let Ok(name_node) = self.get_name_node(id) {
+                if name_node.short_id().as_bytes() == suffix {
                     if let Ok(node) = self.get_node(&name_node.link) {
-                        return Some(node);
-                    } else {
-                        continue;
+                        return Some((name_node, node));
                     }
                 }
             }
@@ -252,31 +1028,114 @@ impl TagFS {
 
         None
     }
-}
 
-impl Filesystem for TagFS {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
-        // TODO: Initiate hashers, lists, etc.
-        // TODO: In future, recover data from a disk image?
-        debug!("init");
+    /// What readdir lists in a tag, with duplicate names told apart
+    fn tag_entries(&self, tag_node: &TagNode) -> Vec<(OsString, u64, FileKind)> {
+        let mut entries = Vec::new();
+        for id in &tag_node.dir_links {
+            if let Ok(name_node) = self.get_name_node(id) {
+                if let Ok(node) = self.get_node(&name_node.link) {
+                    let attr = node.attr();
+                    let name = name_node.name.clone();
+                    entries.push((name, Some(name_node), attr.inode, attr.kind));
+                }
+            }
+        }
 
-        // Create a fake root dir (sort of like 'all tags')
-        let mut fake_root = TagNode::new(TagFS::get_inode_cur(&mut self.inode_cur), None);
+        disambiguate(entries)
+    }
+}
 
-        // Create a simple test file too
-        let file_node = FileNode::new(
-            &mut self.hasher,
-            TagFS::get_inode_cur(&mut self.inode_cur),
-            None,
-        );
-        let name_node = NameNode::new("file1".into(), Node::File(file_node.hash.clone()));
-        fake_root.add_file(&name_node);
+/// Makes every name in a listing unique, keeping the order. The first one keeps it as it is,
+/// the others get the short id of their name node appended, which `search_name` understands.
+/// Entries without a name node go by something unique already.
+fn disambiguate(
+    entries: impl IntoIterator<Item = (OsString, Option<NameNode>, u64, FileKind)>,
+) -> Vec<(OsString, u64, FileKind)> {
+    let mut taken = BTreeSet::new();
+    let mut unique = Vec::new();
+    for (name, name_node, inode, kind) in entries {
+        let name = match name_node {
+            Some(name_node) if taken.contains(&name) => {
+                let mut name = name;
+                name.push(OsStr::from_bytes(&[NAME_SUFFIX_SEPARATOR]));
+                name.push(name_node.short_id());
+                name
+            }
+            _ => name,
+        };
+        taken.insert(name.clone());
+        unique.push((name, inode, kind));
+    }
 
-        self.insert_inode(&INode::File(file_node));
-        self.insert_inode(&INode::Tag(fake_root));
-        self.insert_name_node(&name_node);
+    unique
+}
+
+// Mirrors the namespace rules the kernel applies to extended attributes
+fn xattr_access(req: &Request, inode: &INode, name: &OsStr, write: bool) -> Result<(), c_int> {
+    let denied = if write { EPERM } else { ENODATA };
+    let name = name.as_bytes();
 
+    if name.starts_with(b"user.") {
+        // Only regular files and directories can carry user attributes
+        match inode {
+            INode::Symlink(_) => Err(denied),
+            _ => Ok(()),
+        }
+    } else if name.starts_with(b"trusted.") {
+        if req.uid() == 0 {
+            Ok(())
+        } else {
+            Err(denied)
+        }
+    } else if name.starts_with(b"security.") {
         Ok(())
+    } else {
+        Err(ENOTSUP)
+    }
+}
+
+// Only root gives files away, owners may change the mode and move a file to their own group
+fn setattr_access(
+    req_uid: u32,
+    req_gid: u32,
+    attrs: &InodeAttributes,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<(), c_int> {
+    if req_uid == 0 {
+        return Ok(());
+    }
+
+    let owner = req_uid == attrs.uid;
+    if mode.is_some() && !owner {
+        return Err(EPERM);
+    }
+    if uid.is_some_and(|uid| uid != attrs.uid) {
+        return Err(EPERM);
+    }
+    if gid.is_some_and(|gid| gid != attrs.gid && (!owner || gid != req_gid)) {
+        return Err(EPERM);
+    }
+    Ok(())
+}
+
+// A zero size is the caller asking how big of a buffer it needs
+fn reply_xattr(size: u32, data: &[u8], reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+impl Filesystem for TagFS {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+        debug!("init");
+        self.open_store()
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
@@ -290,33 +1149,31 @@ impl Filesystem for TagFS {
         //let fake_root_dir_attr = InodeAttributes::new_file_attr(1, FileKind::Directory, 0x755);
         let os_name = &name.to_os_string();
 
-        // Iterate through every name node we point to, check whether any names are the same
-        // TODO: Instead of just pointing to UUIDs possibly point to names too to speed this up?
-        if let Ok(x) = self.get_inode(parent) {
-            if let INode::Tag(t) = x {
-                if let Some(node) = self.search_name(&t, os_name) {
-                    match node {
-                        INode::File(f) => {
-                            reply.entry(&TTL, &f.file_attr.into(), 0);
-                        }
-                        INode::Tag(t) => {
-                            reply.entry(&TTL, &t.dir_attr.into(), 0);
-                        }
-                    }
+        // Tags keep an index of their names, so only name nodes that match get read
+        if self.directory_view(parent).is_none() {
+            if let Ok(INode::Tag(t)) = self.get_inode(parent) {
+                if let Some((_, node)) = self.search_name(&t, os_name) {
+                    reply.entry(&TTL, &self.inode_attr(node), 0);
                     return;
                 }
             }
         }
 
-        reply.error(ENOENT);
+        // Stored names shadow queries that happen to be spelled the same
+        match self.lookup_view(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr | ino: {}", ino);
         if let Ok(node) = self.get_inode(ino) {
-            match node {
-                INode::File(f) => reply.attr(&TTL, &f.file_attr.into()),
-                INode::Tag(t) => reply.attr(&TTL, &t.dir_attr.into()),
+            reply.attr(&TTL, &self.inode_attr(node));
+        } else if self.views.get(ino).is_some() {
+            match self.view_attr(ino) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(error_code) => reply.error(error_code),
             }
         } else {
             reply.error(ENOENT);
@@ -338,29 +1195,22 @@ impl Filesystem for TagFS {
 
         // TODO: Still not proper block hashings
 
-        let mut path = PathBuf::from(&self.data_dir);
         if let Ok(node) = self.get_inode(ino) {
-            match node {
-                INode::File(f) => {
-                    path.push("filenodes");
-                    path.push(f.hash.code.clone());
-                }
+            let key = match node {
+                INode::File(f) => f.key(),
                 INode::Tag(_) => {
                     reply.error(EISDIR);
                     return;
                 }
-            }
-
-            if let Ok(file) = File::open(&path) {
-                let file_size = file.metadata().unwrap().len();
-                // Could underflow if file length is less than local_start
-                let read_size = min(size, file_size.saturating_sub(offset as u64) as u32);
+                INode::Symlink(_) => {
+                    reply.error(EINVAL);
+                    return;
+                }
+            };
 
-                let mut buffer = vec![0; read_size as usize];
-                file.read_exact_at(&mut buffer, offset as u64).unwrap();
-                reply.data(&buffer);
-            } else {
-                reply.error(ENOENT);
+            match self.store.read_blob(&key, offset as u64, size) {
+                Ok(buffer) => reply.data(&buffer),
+                Err(_) => reply.error(ENOENT),
             }
         } else {
             reply.error(ENOENT);
@@ -377,42 +1227,37 @@ impl Filesystem for TagFS {
     ) {
         debug!("readdir | ino: {}; offset: {}", ino, offset);
 
-        if let Ok(INode::Tag(t)) = self.get_inode(ino) {
-            let entries = t.dir_links;
-
-            for (index, id) in entries.iter().skip(offset as usize).enumerate() {
-                if let Ok(name_node) = self.get_name_node(&id) {
-                    let (inode, file_type) = {
-                        if let Ok(node) = self.get_node(&name_node.link) {
-                            match node {
-                                INode::File(f) => (f.file_attr.inode, f.file_attr.kind),
-                                INode::Tag(t) => (t.dir_attr.inode, t.dir_attr.kind),
-                            }
-                        } else {
-                            continue;
-                        }
-                    };
-                    debug!("\t> {inode}, {file_type:?}, {:?}", name_node.name);
-
-                    // i + 1 means the index of the next entry
-                    // i-node, offset, type, name
-                    let buffer_full: bool = reply.add(
-                        inode,
-                        offset + index as i64 + 1,
-                        file_type.into(),
-                        name_node.name,
-                    );
-
-                    if buffer_full {
-                        break;
-                    }
-                }
+        let entries = if let Some(view) = self.directory_view(ino) {
+            self.view_entries(&view)
+        } else if let Ok(INode::Tag(t)) = self.get_inode(ino) {
+            Ok(self.tag_entries(&t))
+        } else {
+            Err(ENOENT)
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
             }
+        };
 
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
+        for (index, (name, inode, file_type)) in
+            entries.into_iter().skip(offset as usize).enumerate()
+        {
+            debug!("\t> {inode}, {file_type:?}, {name:?}");
+
+            // i + 1 means the index of the next entry
+            // i-node, offset, type, name
+            let buffer_full: bool =
+                reply.add(inode, offset + index as i64 + 1, file_type.into(), name);
+
+            if buffer_full {
+                break;
+            }
         }
+
+        reply.ok();
     }
 
     fn create(
@@ -426,39 +1271,25 @@ impl Filesystem for TagFS {
         reply: ReplyCreate,
     ) {
         debug!("create | parent: {parent}, name: {name:?}");
-        let mut parent_attrs = match self.get_inode(parent) {
-            Ok(attrs) => match attrs {
-                INode::File(f) => f.file_attr,
-                INode::Tag(t) => t.dir_attr,
-            },
-            Err(error_code) => {
-                reply.error(error_code);
-                return;
-            }
-        };
 
         // TODO: access checks
-        parent_attrs.last_modified = time_now();
-        parent_attrs.last_metadata_changed = time_now();
-
         if req.uid() != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+            mode &= !(libc::S_ISUID | libc::S_ISGID);
         }
 
-        let file_type = mode & libc::S_IFMT as u32;
+        let file_type = mode & libc::S_IFMT;
 
         let file_type = match file_type {
             libc::S_IFREG => FileKind::File,
             libc::S_IFDIR => FileKind::Directory,
             _ => {
-                reply.error(libc::ENOSYS);
+                reply.error(ENOSYS);
                 unimplemented!("mknod() implementation is incomplete. Only supports regular files and directories. Got {:o}", mode);
             }
         };
 
         let attrs = InodeAttributes {
             inode: 0,
-            open_file_handles: 1,
             size: 0,
             last_accessed: time_now(),
             last_modified: time_now(),
@@ -468,46 +1299,18 @@ impl Filesystem for TagFS {
             hardlinks: 1,
             uid: req.uid(),
             gid: req.gid(),
-        };
-        let mut inode = self.allocate_next_inode(file_type, Some(attrs));
-
-        let parent_node = self.get_node_from_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = inode {
-            t.add_file(&NameNode::new(".".into(), Node::Tag(t.id)));
-            t.add_file(&NameNode::new("..".into(), parent_node));
+            xattrs: BTreeMap::new(),
         };
 
-        let mut parent_inode = self.get_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = parent_inode {
-            let name_node = NameNode::new(name.to_os_string(), inode.to_node());
-            t.add_file(&name_node);
-            self.insert_name_node(&name_node);
-            self.insert_inode(&parent_inode);
-        }
-
-        // TODO: make it so after every modification inodes rewrite themselves?
-        self.insert_inode(&inode);
-
         // TODO: implement flags
-        match inode {
-            INode::File(f) => {
-                reply.created(
-                    &Duration::new(0, 0),
-                    &f.file_attr.into(),
-                    0,
-                    self.get_filehandle_cur(),
-                    0,
-                );
-            }
-            INode::Tag(t) => {
-                reply.created(
-                    &Duration::new(0, 0),
-                    &t.dir_attr.into(),
-                    0,
-                    self.get_filehandle_cur(),
-                    0,
-                );
+        let created = self.create_node(parent, name, attrs);
+        match created.and_then(|inode| self.sync().map(|()| inode)) {
+            Ok(inode) => {
+                *self.open_handles.entry(inode.attr().inode).or_default() += 1;
+                let fh = self.get_filehandle_cur();
+                reply.created(&Duration::new(0, 0), &inode.into_attr().into(), 0, fh, 0);
             }
+            Err(error_code) => reply.error(error_code),
         }
     }
 
@@ -526,13 +1329,13 @@ impl Filesystem for TagFS {
     ) {
         debug!("mknod");
 
-        let file_type = mode & libc::S_IFMT as u32;
+        let file_type = mode & libc::S_IFMT;
 
         let file_type = match file_type {
             libc::S_IFREG => FileKind::File,
             libc::S_IFDIR => FileKind::Directory,
             _ => {
-                reply.error(libc::ENOSYS);
+                reply.error(ENOSYS);
                 unimplemented!("mknod() implementation is incomplete. Only supports regular files and directories. Got {:o}", mode);
             }
         };
@@ -540,28 +1343,13 @@ impl Filesystem for TagFS {
         // We can't return EEXIST sort of - we can create an arbitrary number of files with the
         // same name, but different content and hash!
 
-        let mut parent_attrs = match self.get_inode(parent) {
-            Ok(attrs) => match attrs {
-                INode::File(f) => f.file_attr,
-                INode::Tag(t) => t.dir_attr,
-            },
-            Err(error_code) => {
-                reply.error(error_code);
-                return;
-            }
-        };
-
         // TODO: access checks
-        parent_attrs.last_modified = time_now();
-        parent_attrs.last_metadata_changed = time_now();
-
         if req.uid() != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+            mode &= !(libc::S_ISUID | libc::S_ISGID);
         }
 
         let attrs = InodeAttributes {
             inode: 0,
-            open_file_handles: 0,
             size: 0,
             last_accessed: time_now(),
             last_modified: time_now(),
@@ -571,30 +1359,14 @@ impl Filesystem for TagFS {
             hardlinks: 1,
             uid: req.uid(),
             gid: req.gid(), // TODO: Proper uid, gid creation
+            xattrs: BTreeMap::new(),
         };
-        let mut inode = self.allocate_next_inode(file_type, Some(attrs));
-
-        let parent_node = self.get_node_from_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = inode {
-            t.add_file(&NameNode::new(".".into(), Node::Tag(t.id)));
-            t.add_file(&NameNode::new("..".into(), parent_node));
-        };
-
-        let mut parent_inode = self.get_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = parent_inode {
-            let name_node = NameNode::new(name.to_os_string(), inode.to_node());
-            t.add_file(&name_node);
-            self.insert_name_node(&name_node);
-            self.insert_inode(&parent_inode);
-        }
-
-        // TODO: make it so after every modification inodes rewrite themselves?
-        self.insert_inode(&inode);
 
         // TODO: implement flags
-        match inode {
-            INode::File(f) => reply.entry(&Duration::new(0, 0), &f.file_attr.into(), 0),
-            INode::Tag(t) => reply.entry(&Duration::new(0, 0), &t.dir_attr.into(), 0),
+        let created = self.create_node(parent, name, attrs);
+        match created.and_then(|inode| self.sync().map(|()| inode)) {
+            Ok(inode) => reply.entry(&Duration::new(0, 0), &inode.into_attr().into(), 0),
+            Err(error_code) => reply.error(error_code),
         }
     }
 
@@ -609,11 +1381,8 @@ impl Filesystem for TagFS {
     ) {
         debug!("mkdir | unimplemented!");
 
-        let mut parent_attrs = match self.get_inode(parent) {
-            Ok(attrs) => match attrs {
-                INode::File(f) => f.file_attr,
-                INode::Tag(t) => t.dir_attr,
-            },
+        let parent_inode = match self.get_inode(parent) {
+            Ok(inode) => inode,
             Err(error_code) => {
                 reply.error(error_code);
                 return;
@@ -621,19 +1390,15 @@ impl Filesystem for TagFS {
         };
 
         // TODO check access
-        parent_attrs.last_modified = time_now();
-        parent_attrs.last_metadata_changed = time_now();
-
         if req.uid() != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+            mode &= !(libc::S_ISUID | libc::S_ISGID);
         }
-        if parent_attrs.mode & libc::S_ISGID as u16 != 0 {
-            mode |= libc::S_ISGID as u32;
+        if parent_inode.attr().mode & libc::S_ISGID as u16 != 0 {
+            mode |= libc::S_ISGID;
         }
 
         let attrs = InodeAttributes {
             inode: 0,
-            open_file_handles: 0,
             size: BLOCK_SIZE,
             last_accessed: time_now(),
             last_modified: time_now(),
@@ -643,184 +1408,340 @@ impl Filesystem for TagFS {
             hardlinks: 2,
             uid: req.uid(),
             gid: req.gid(),
+            xattrs: BTreeMap::new(),
         };
-        let mut inode = self.allocate_next_inode(FileKind::Directory, Some(attrs));
 
-        let parent_node = self.get_node_from_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = inode {
-            t.add_file(&NameNode::new(".".into(), Node::Tag(t.id)));
-            t.add_file(&NameNode::new("..".into(), parent_node));
+        let created = self.create_node(parent, name, attrs);
+        match created.and_then(|inode| self.sync().map(|()| inode)) {
+            Ok(inode) => reply.entry(&TTL, &inode.into_attr().into(), 0),
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    // NOTE: The calls below this point that are still unimplemented return their default return
+    // values, while also debug printing some information so we could use that while developing and
+    // determining which functions need to be implemented for certain functionality to work
+    //
+    // TODO: Figure out what exactly is needed for simple functionality
+    // As far as I can tell:
+    //  * touch also calls setattr!
+    //  * file attributes changing
+    //  * "directory" creation
+    //  * moving files and tags
+
+    fn destroy(&mut self) {
+        debug!("destroy");
+        if let Err(e) = self.flush_cache() {
+            error!("destroy | {e}");
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
+        debug!("forget | unimplemented!");
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr | ino: {ino}, mode: {mode:?}, uid: {uid:?}, gid: {gid:?}, size: {size:?}");
+
+        if let Err(error_code) = self.writable() {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut inode = match self.get_inode(ino) {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
         };
 
-        let mut parent_inode = self.get_inode(parent).unwrap();
-        if let INode::Tag(ref mut t) = parent_inode {
-            let name_node = NameNode::new(name.to_os_string(), inode.to_node());
-            t.add_file(&name_node);
-            self.insert_name_node(&name_node);
-            self.insert_inode(&parent_inode);
+        let attrs = inode.attr_mut();
+        if let Err(error_code) = setattr_access(req.uid(), req.gid(), attrs, mode, uid, gid) {
+            reply.error(error_code);
+            return;
+        }
+        if let Some(mode) = mode {
+            attrs.mode = mode as u16;
+            // Like the kernel, the group bit only sticks for members of the group
+            if req.uid() != 0 && req.gid() != attrs.gid {
+                attrs.mode &= !libc::S_ISGID as u16;
+            }
+        }
+        if uid.is_some() || gid.is_some() {
+            if let Some(uid) = uid {
+                attrs.uid = uid;
+            }
+            if let Some(gid) = gid {
+                attrs.gid = gid;
+            }
+            if req.uid() != 0 {
+                attrs.mode &= !(libc::S_ISUID | libc::S_ISGID) as u16;
+            }
+        }
+        if let Some(atime) = atime {
+            attrs.last_accessed = match atime {
+                TimeOrNow::SpecificTime(time) => time_from_system_time(&time),
+                TimeOrNow::Now => time_now(),
+            };
+        }
+        if let Some(mtime) = mtime {
+            attrs.last_modified = match mtime {
+                TimeOrNow::SpecificTime(time) => time_from_system_time(&time),
+                TimeOrNow::Now => time_now(),
+            };
+        }
+        attrs.last_metadata_changed = match ctime {
+            Some(time) => time_from_system_time(&time),
+            None => time_now(),
+        };
+
+        if let Some(size) = size {
+            match inode {
+                INode::File(ref mut f) => {
+                    if let Err(e) = self.store.truncate_blob(&f.key(), size) {
+                        reply.error(e.raw_os_error().unwrap_or(EIO));
+                        return;
+                    }
+
+                    f.file_attr.size = size;
+                    if mtime.is_none() {
+                        f.file_attr.last_modified = time_now();
+                    }
+                    self.dirty_files.insert(ino);
+                }
+                INode::Tag(_) => {
+                    reply.error(EISDIR);
+                    return;
+                }
+                INode::Symlink(_) => {
+                    reply.error(EINVAL);
+                    return;
+                }
+            }
         }
 
-        // TODO: make it so after every modification inodes rewrite themselves?
         self.insert_inode(&inode);
 
-        // TODO: implement flags
-        match inode {
-            INode::File(_) => reply.error(ENOSYS),
-            INode::Tag(t) => reply.entry(&TTL, &t.dir_attr.into(), 0),
+        // Truncating changes the content, so the file moves to its new hash right away
+        if size.is_some() {
+            if let Err(error_code) = self.rehash_file_node(ino) {
+                reply.error(error_code);
+                return;
+            }
+        }
+        if let Err(error_code) = self.sync() {
+            reply.error(error_code);
+            return;
+        }
+
+        match self.get_inode(ino) {
+            Ok(inode) => reply.attr(&TTL, &self.inode_attr(inode)),
+            Err(error_code) => reply.error(error_code),
         }
     }
 
-    // NOTE: All the calls below this point are unimplemented, and return their default return
-    // values, while also debug printing some information so we could use that while developing and
-    // determining which functions need to be implemented for certain functionality to work
-    //
-    // TODO: Figure out what exactly is needed for simple functionality
-    // As far as I can tell:
-    //  * touch also calls setattr!
-    //  * file attributes changing
-    //  * "directory" creation
-    //  * moving files and tags
-
-    fn destroy(&mut self) {
-        debug!("destroy | unimplemented!");
-    }
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        debug!("readlink | ino: {ino}");
 
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {
-        debug!("forget | unimplemented!");
+        match self.get_inode(ino) {
+            Ok(INode::Symlink(l)) => reply.data(l.target.as_os_str().as_bytes()),
+            Ok(_) => reply.error(EINVAL),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        debug!("setattr | unimplemented!");
-        reply.error(ENOSYS);
-    }
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink | parent: {parent}, name: {name:?}");
 
-    fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
-        debug!("readlink | unimplemented!");
-        reply.error(ENOSYS);
+        match self.unlink_name(parent, name).and_then(|()| self.sync()) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!("unlink | unimplemented!");
-        reply.error(ENOSYS);
-    }
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir | parent: {parent}, name: {name:?}");
 
-    fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!("rmdir | unimplemented!");
-        reply.error(ENOSYS);
+        match self.rmdir_name(parent, name).and_then(|()| self.sync()) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn symlink(
         &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _link: &Path,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
         reply: ReplyEntry,
     ) {
-        debug!("rmdir | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("symlink | parent: {parent}, name: {name:?}, link: {link:?}");
+
+        let attrs = InodeAttributes {
+            inode: 0,
+            size: link.as_os_str().len() as u64,
+            last_accessed: time_now(),
+            last_modified: time_now(),
+            last_metadata_changed: time_now(),
+            kind: FileKind::Symlink,
+            mode: 0o777,
+            hardlinks: 1,
+            uid: req.uid(),
+            gid: req.gid(),
+            xattrs: BTreeMap::new(),
+        };
+        let inode = match self.create_symlink(parent, name, link, attrs) {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(error_code) = self.sync() {
+            reply.error(error_code);
+            return;
+        }
+
+        reply.entry(&TTL, &inode.into_attr().into(), 0);
     }
 
     fn rename(
         &mut self,
         _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _newparent: u64,
-        _newname: &OsStr,
-        _flags: u32,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        debug!("rename | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("rename | parent: {parent}, name: {name:?}, newparent: {newparent}, newname: {newname:?}, flags: {flags:#x}");
+
+        let renamed = self.rename_name(parent, name, newparent, newname, flags);
+        match renamed.and_then(|()| self.sync()) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn link(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &OsStr,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        debug!("link | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("link | ino: {ino}, newparent: {newparent}, newname: {newname:?}");
+
+        let linked = self.link_name(ino, newparent, newname);
+        match linked.and_then(|inode| self.sync().map(|()| inode)) {
+            Ok(inode) => reply.entry(&TTL, &self.inode_attr(inode), 0),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        debug!("open | unimplemented!");
-        reply.opened(0, 0);
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open | ino: {ino}");
+
+        match self.open_file(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn write(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        _data: &[u8],
+        offset: i64,
+        data: &[u8],
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        debug!("write | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("write | ino: {ino}; offset: {offset}; size: {}", data.len());
+
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        match self.write_file(ino, offset as u64, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn flush(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _lock_owner: u64,
         reply: ReplyEmpty,
     ) {
-        debug!("flush | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("flush | ino: {ino}");
+
+        match self.flush_file(ino) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        debug!("release | unimplemented!");
-        reply.ok();
+        debug!("release | ino: {ino}");
+
+        match self.release_file(ino) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn fsync(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        debug!("fsync | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("fsync | ino: {ino}");
+
+        match self.flush_file(ino) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
@@ -860,8 +1781,12 @@ impl Filesystem for TagFS {
         _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        debug!("fsyncdir | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("fsyncdir");
+
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
@@ -871,38 +1796,174 @@ impl Filesystem for TagFS {
 
     fn setxattr(
         &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
-        _flags: i32,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
         _position: u32,
         reply: ReplyEmpty,
     ) {
-        debug!("setxattr | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("setxattr | ino: {ino}, name: {name:?}, flags: {flags:#x}");
+
+        if let Err(error_code) = self.writable() {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut inode = match self.get_inode(ino) {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(error_code) = xattr_access(req, &inode, name, true) {
+            reply.error(error_code);
+            return;
+        }
+
+        if name == TAGS_XATTR {
+            match self.set_tags_xattr(ino, value).and_then(|()| self.sync()) {
+                Ok(()) => reply.ok(),
+                Err(error_code) => reply.error(error_code),
+            }
+            return;
+        }
+
+        let attrs = inode.attr_mut();
+        let key = name.as_bytes().to_vec();
+        if flags & libc::XATTR_CREATE != 0 && attrs.xattrs.contains_key(&key) {
+            reply.error(EEXIST);
+            return;
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !attrs.xattrs.contains_key(&key) {
+            reply.error(ENODATA);
+            return;
+        }
+
+        attrs.xattrs.insert(key, value.to_vec());
+        attrs.last_metadata_changed = time_now();
+        self.insert_inode(&inode);
+
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn getxattr(
         &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
         reply: ReplyXattr,
     ) {
-        debug!("getxattr | unimplemented!");
-        reply.error(ENOSYS);
+        debug!("getxattr | ino: {ino}, name: {name:?}, size: {size}");
+
+        let inode = match self.get_inode(ino) {
+            Ok(inode) => inode,
+            Err(_) if self.views.get(ino).is_some() => {
+                reply.error(ENODATA);
+                return;
+            }
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(error_code) = xattr_access(req, &inode, name, false) {
+            reply.error(error_code);
+            return;
+        }
+
+        if name == TAGS_XATTR {
+            match inode {
+                INode::Tag(_) => reply.error(ENODATA),
+                _ => reply_xattr(size, &self.get_tags_xattr(&inode), reply),
+            }
+            return;
+        }
+
+        match inode.attr().xattrs.get(name.as_bytes()) {
+            Some(value) => reply_xattr(size, value, reply),
+            None => reply.error(ENODATA),
+        }
     }
 
-    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
-        debug!("listxattr | unimplemented!");
-        reply.error(ENOSYS);
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr | ino: {ino}, size: {size}");
+
+        let inode = match self.get_inode(ino) {
+            Ok(inode) => inode,
+            Err(_) if self.views.get(ino).is_some() => {
+                reply_xattr(size, &[], reply);
+                return;
+            }
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        // Null-terminated names, skipping the ones the caller isn't allowed to see
+        let mut names = Vec::new();
+        if let INode::File(_) = inode {
+            names.extend_from_slice(TAGS_XATTR.as_bytes());
+            names.push(0);
+        }
+        for key in inode.attr().xattrs.keys() {
+            if xattr_access(req, &inode, OsStr::from_bytes(key), false).is_ok() {
+                names.extend_from_slice(key);
+                names.push(0);
+            }
+        }
+
+        reply_xattr(size, &names, reply);
     }
 
-    fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!("removexattr | unimplemented!");
-        reply.error(ENOSYS);
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr | ino: {ino}, name: {name:?}");
+
+        if let Err(error_code) = self.writable() {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut inode = match self.get_inode(ino) {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(error_code) = xattr_access(req, &inode, name, true) {
+            reply.error(error_code);
+            return;
+        }
+
+        // Same as untagging the file from everything
+        if name == TAGS_XATTR {
+            match self.set_tags_xattr(ino, &[]).and_then(|()| self.sync()) {
+                Ok(()) => reply.ok(),
+                Err(error_code) => reply.error(error_code),
+            }
+            return;
+        }
+
+        let attrs = inode.attr_mut();
+        if attrs.xattrs.remove(name.as_bytes()).is_none() {
+            reply.error(ENODATA);
+            return;
+        }
+        attrs.last_metadata_changed = time_now();
+        self.insert_inode(&inode);
+
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
     }
 
     fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: ReplyEmpty) {
@@ -1014,3 +2075,418 @@ impl Filesystem for TagFS {
         reply.error(ENOSYS);
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::fs::store::MemoryStore;
+
+    pub(crate) const ROOT: u64 = 1;
+
+    pub(crate) fn mounted() -> TagFS {
+        let mut fs = TagFS::new(
+            Box::new(MemoryStore::default()),
+            false,
+            false,
+            NonZeroUsize::new(64).unwrap(),
+            None,
+        );
+        fs.open_store().unwrap();
+        fs
+    }
+
+    pub(crate) fn create(fs: &mut TagFS, parent: u64, name: &str, kind: FileKind) -> u64 {
+        let mut attrs = InodeAttributes::new_file_attr(0, kind, 0o644);
+        attrs.hardlinks = if kind == FileKind::Directory { 2 } else { 1 };
+        fs.create_node(parent, name.as_ref(), attrs)
+            .unwrap()
+            .attr()
+            .inode
+    }
+
+    pub(crate) fn write(fs: &mut TagFS, ino: u64, data: &[u8]) {
+        fs.write_file(ino, 0, data).unwrap();
+        fs.flush_file(ino).unwrap();
+    }
+
+    // A tag holding a file that's also in the root, another file and a symlink, all written back
+    pub(crate) fn populated() -> TagFS {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        let song = create(&mut fs, music, "song", FileKind::File);
+        write(&mut fs, song, b"la la");
+        fs.link_name(song, ROOT, "song".as_ref()).unwrap();
+        let notes = create(&mut fs, ROOT, "notes", FileKind::File);
+        write(&mut fs, notes, b"buy milk");
+        create(&mut fs, music, "link", FileKind::Symlink);
+        fs.sync().unwrap();
+        fs
+    }
+
+    fn read(fs: &TagFS, ino: u64) -> Vec<u8> {
+        match fs.get_inode(ino).unwrap() {
+            INode::File(f) => fs.store.read_blob(&f.key(), 0, 4096).unwrap(),
+            _ => panic!("{ino} isn't a file"),
+        }
+    }
+
+    // What a tag lists, sorted
+    fn names(fs: &TagFS, tag: u64) -> Vec<(String, u64)> {
+        let tag_node = fs.get_tag_node(tag).unwrap();
+        let mut names: Vec<_> = tag_node
+            .dir_links
+            .iter()
+            .filter_map(|id| fs.get_name_node(id).ok())
+            .map(|n| {
+                let ino = fs.get_node(&n.link).unwrap().attr().inode;
+                (n.name.into_string().unwrap(), ino)
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    // The tags of a file, sorted
+    fn tags(fs: &TagFS, ino: u64) -> String {
+        let inode = fs.get_inode(ino).unwrap();
+        let tags = String::from_utf8(fs.get_tags_xattr(&inode)).unwrap();
+        let mut tags: Vec<_> = tags.lines().collect();
+        tags.sort();
+        tags.join("\n")
+    }
+
+    #[test]
+    fn tagging_a_file_names_it_in_every_tag() {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        let rock = create(&mut fs, ROOT, "rock", FileKind::Directory);
+        let song = create(&mut fs, ROOT, "song", FileKind::File);
+
+        fs.set_tags_xattr(song, b"music\nrock").unwrap();
+        assert_eq!(names(&fs, music), [("song".to_string(), song)]);
+        assert_eq!(names(&fs, rock), [("song".to_string(), song)]);
+        assert_eq!(tags(&fs, song), "music\nrock");
+        assert_eq!(fs.get_inode(song).unwrap().attr().hardlinks, 3);
+
+        fs.set_tags_xattr(song, b"rock").unwrap();
+        assert!(names(&fs, music).is_empty());
+        assert_eq!(tags(&fs, song), "rock");
+
+        assert_eq!(fs.set_tags_xattr(song, b"jazz"), Err(ENOENT));
+        assert_eq!(fs.set_tags_xattr(music, b"rock"), Err(EPERM));
+    }
+
+    #[test]
+    fn untagging_a_file_keeps_it_in_the_root() {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        let song = create(&mut fs, music, "song", FileKind::File);
+
+        fs.set_tags_xattr(song, b"").unwrap();
+        assert!(names(&fs, music).is_empty());
+        assert!(names(&fs, ROOT).contains(&("song".to_string(), song)));
+        assert_eq!(fs.get_inode(song).unwrap().attr().hardlinks, 1);
+    }
+
+    #[test]
+    fn renaming_over_a_name_replaces_it() {
+        let mut fs = mounted();
+        let a = create(&mut fs, ROOT, "a", FileKind::File);
+        let b = create(&mut fs, ROOT, "b", FileKind::File);
+        write(&mut fs, a, b"new");
+        write(&mut fs, b, b"old");
+
+        assert_eq!(
+            fs.rename_name(
+                ROOT,
+                "a".as_ref(),
+                ROOT,
+                "b".as_ref(),
+                libc::RENAME_NOREPLACE
+            ),
+            Err(EEXIST)
+        );
+        fs.rename_name(ROOT, "a".as_ref(), ROOT, "b".as_ref(), 0)
+            .unwrap();
+        assert_eq!(names(&fs, ROOT), [("b".to_string(), a)]);
+        assert_eq!(read(&fs, a), b"new");
+        // Nothing else had the replaced file open
+        assert!(matches!(fs.get_inode(b), Err(ENOENT)));
+    }
+
+    #[test]
+    fn renaming_moves_names_between_tags() {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        let a = create(&mut fs, ROOT, "a", FileKind::File);
+        let b = create(&mut fs, music, "b", FileKind::File);
+
+        fs.rename_name(
+            ROOT,
+            "a".as_ref(),
+            music,
+            "b".as_ref(),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        assert_eq!(names(&fs, music), [("b".to_string(), a)]);
+        assert!(names(&fs, ROOT).contains(&("a".to_string(), b)));
+
+        fs.rename_name(music, "b".as_ref(), ROOT, "c".as_ref(), 0)
+            .unwrap();
+        assert!(names(&fs, music).is_empty());
+        assert!(names(&fs, ROOT).contains(&("c".to_string(), a)));
+    }
+
+    #[test]
+    fn unlinking_the_last_name_removes_the_file() {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        let song = create(&mut fs, ROOT, "song", FileKind::File);
+        write(&mut fs, song, b"la");
+        fs.link_name(song, music, "song".as_ref()).unwrap();
+
+        fs.unlink_name(ROOT, "song".as_ref()).unwrap();
+        assert_eq!(read(&fs, song), b"la");
+        assert_eq!(fs.unlink_name(ROOT, "song".as_ref()), Err(ENOENT));
+        assert_eq!(fs.unlink_name(ROOT, "music".as_ref()), Err(EISDIR));
+
+        fs.unlink_name(music, "song".as_ref()).unwrap();
+        fs.sync().unwrap();
+        assert!(matches!(fs.get_inode(song), Err(ENOENT)));
+        assert!(fs.store.list_files().is_empty());
+        assert!(fs.store.list_blobs().is_empty());
+    }
+
+    #[test]
+    fn open_files_outlive_their_last_name() {
+        let mut fs = mounted();
+        let song = create(&mut fs, ROOT, "song", FileKind::File);
+        write(&mut fs, song, b"la");
+        fs.open_file(song, libc::O_RDONLY).unwrap();
+        fs.open_file(song, libc::O_RDWR).unwrap();
+
+        fs.unlink_name(ROOT, "song".as_ref()).unwrap();
+        fs.sync().unwrap();
+        gc::collect(&*fs.store, |ino| fs.open_handles.contains_key(&ino)).unwrap();
+        assert_eq!(read(&fs, song), b"la");
+
+        fs.release_file(song).unwrap();
+        assert_eq!(read(&fs, song), b"la");
+        fs.release_file(song).unwrap();
+        assert!(matches!(fs.get_inode(song), Err(ENOENT)));
+        assert!(fs.open_handles.is_empty());
+    }
+
+    #[test]
+    fn nothing_changes_when_mounted_read_only() {
+        let mut fs = populated();
+        let store = std::mem::replace(&mut fs.store, Box::new(MemoryStore::default()));
+        let mut fs = TagFS::new(store, true, false, NonZeroUsize::new(64).unwrap(), None);
+        fs.open_store().unwrap();
+        let before = names(&fs, ROOT);
+        let (_, song) = before
+            .iter()
+            .find(|(name, _)| name == "song")
+            .unwrap()
+            .clone();
+
+        assert_eq!(fs.unlink_name(ROOT, "song".as_ref()), Err(EROFS));
+        assert_eq!(fs.rmdir_name(ROOT, "music".as_ref()), Err(EROFS));
+        assert_eq!(
+            fs.rename_name(ROOT, "song".as_ref(), ROOT, "tune".as_ref(), 0),
+            Err(EROFS)
+        );
+        assert!(matches!(
+            fs.link_name(song, ROOT, "tune".as_ref()),
+            Err(EROFS)
+        ));
+        assert_eq!(fs.set_tags_xattr(song, b""), Err(EROFS));
+        assert_eq!(fs.write_file(song, 0, b"do"), Err(EROFS));
+        assert_eq!(fs.open_file(song, libc::O_WRONLY), Err(EROFS));
+
+        fs.open_file(song, libc::O_RDONLY).unwrap();
+        fs.flush_file(song).unwrap();
+        fs.release_file(song).unwrap();
+        assert_eq!(names(&fs, ROOT), before);
+        assert_eq!(read(&fs, song), b"la la");
+    }
+
+    #[test]
+    fn empty_stores_are_not_mounted_read_only() {
+        let store = Box::new(MemoryStore::default());
+        let mut fs = TagFS::new(store, true, false, NonZeroUsize::new(64).unwrap(), None);
+        assert_eq!(fs.open_store(), Err(EROFS));
+        assert!(fs.store.is_empty());
+    }
+
+    #[test]
+    fn only_owners_change_modes_and_only_root_gives_files_away() {
+        let mut attrs = InodeAttributes::new_file_attr(2, FileKind::File, 0o644);
+        (attrs.uid, attrs.gid) = (1000, 100);
+
+        assert_eq!(
+            setattr_access(1000, 100, &attrs, Some(0o600), None, None),
+            Ok(())
+        );
+        assert_eq!(
+            setattr_access(1001, 100, &attrs, Some(0o600), None, None),
+            Err(EPERM)
+        );
+        assert_eq!(
+            setattr_access(0, 0, &attrs, Some(0o600), None, None),
+            Ok(())
+        );
+
+        assert_eq!(
+            setattr_access(1000, 100, &attrs, None, Some(1001), None),
+            Err(EPERM)
+        );
+        assert_eq!(
+            setattr_access(1000, 100, &attrs, None, Some(1000), None),
+            Ok(())
+        );
+        assert_eq!(
+            setattr_access(0, 0, &attrs, None, Some(1001), Some(0)),
+            Ok(())
+        );
+
+        // Owners may only hand a file to the group they're in themselves
+        assert_eq!(
+            setattr_access(1000, 50, &attrs, None, None, Some(50)),
+            Ok(())
+        );
+        assert_eq!(
+            setattr_access(1000, 100, &attrs, None, None, Some(50)),
+            Err(EPERM)
+        );
+        assert_eq!(
+            setattr_access(1001, 50, &attrs, None, None, Some(50)),
+            Err(EPERM)
+        );
+    }
+
+    #[test]
+    fn only_empty_tags_are_removed() {
+        let mut fs = mounted();
+        let music = create(&mut fs, ROOT, "music", FileKind::Directory);
+        create(&mut fs, music, "song", FileKind::File);
+
+        assert_eq!(fs.rmdir_name(ROOT, "music".as_ref()), Err(ENOTEMPTY));
+        fs.unlink_name(music, "song".as_ref()).unwrap();
+        fs.rmdir_name(ROOT, "music".as_ref()).unwrap();
+        assert!(names(&fs, ROOT).is_empty());
+        assert!(matches!(fs.get_inode(music), Err(ENOENT)));
+    }
+
+    #[test]
+    fn files_with_the_same_content_stay_apart() {
+        let mut fs = mounted();
+        let a = create(&mut fs, ROOT, "a", FileKind::File);
+        let b = create(&mut fs, ROOT, "b", FileKind::File);
+        write(&mut fs, a, b"same");
+        write(&mut fs, b, b"same");
+        assert_eq!(fs.store.list_files().len(), 2);
+
+        fs.unlink_name(ROOT, "a".as_ref()).unwrap();
+        fs.sync().unwrap();
+        assert_eq!(read(&fs, b), b"same");
+    }
+
+    #[test]
+    fn garbage_is_collected_when_metadata_changes() {
+        let store = Box::new(MemoryStore::default());
+        let cache_size = NonZeroUsize::new(64).unwrap();
+        let mut fs = TagFS::new(store, false, false, cache_size, Some(Duration::ZERO));
+        fs.open_store().unwrap();
+        // A file nothing refers to, as if it had been left behind
+        let lost = FileNode::new(&mut Sha3_256::new(), 99, None);
+        fs.store.store_node(&INode::File(lost)).unwrap();
+
+        create(&mut fs, ROOT, "music", FileKind::Directory);
+        fs.sync().unwrap();
+        assert!(fs.store.list_files().is_empty());
+        assert_eq!(fs.store.list_tags().len(), 2);
+    }
+
+    #[test]
+    fn symlinks_are_as_large_as_their_target() {
+        let mut fs = mounted();
+        let attrs = InodeAttributes::new_file_attr(0, FileKind::Symlink, 0o777);
+        let link = fs
+            .create_symlink(ROOT, "link".as_ref(), "../music/song".as_ref(), attrs)
+            .unwrap()
+            .attr()
+            .inode;
+        fs.sync().unwrap();
+
+        let inode = fs.get_inode(link).unwrap();
+        assert_eq!(
+            fs.inode_attr(fs.get_inode(link).unwrap()).size,
+            "../music/song".len() as u64
+        );
+        let INode::Symlink(l) = inode else {
+            panic!("{link} isn't a symlink");
+        };
+        assert_eq!(l.target, Path::new("../music/song"));
+    }
+
+    // What readdir lists in a tag, sorted
+    fn listed(fs: &TagFS, tag: u64) -> Vec<(String, u64)> {
+        let tag_node = fs.get_tag_node(tag).unwrap();
+        let mut listed: Vec<_> = fs
+            .tag_entries(&tag_node)
+            .into_iter()
+            .map(|(name, ino, _)| (name.into_string().unwrap(), ino))
+            .collect();
+        listed.sort();
+        listed
+    }
+
+    // The name a duplicate is listed under, besides the plain one
+    fn suffixed(fs: &TagFS, tag: u64, name: &str) -> String {
+        listed(fs, tag)
+            .into_iter()
+            .map(|(listed, _)| listed)
+            .find(|listed| listed.starts_with(&format!("{name}~")))
+            .unwrap()
+    }
+
+    #[test]
+    fn duplicate_names_keep_their_suffix_when_files_change() {
+        let mut fs = mounted();
+        let a = create(&mut fs, ROOT, "song", FileKind::File);
+        let b = create(&mut fs, ROOT, "song", FileKind::File);
+        write(&mut fs, a, b"la");
+        write(&mut fs, b, b"do");
+        let duplicate = suffixed(&fs, ROOT, "song");
+        assert_eq!(listed(&fs, ROOT).len(), 2);
+
+        write(&mut fs, a, b"la la");
+        write(&mut fs, b, b"do re");
+        assert_eq!(suffixed(&fs, ROOT, "song"), duplicate);
+        let (_, inode) = fs
+            .search_name(&fs.get_tag_node(ROOT).unwrap(), duplicate.as_ref())
+            .unwrap();
+        let other = if inode.attr().inode == a { b } else { a };
+        assert!(listed(&fs, ROOT).contains(&("song".to_string(), other)));
+    }
+
+    #[test]
+    fn the_same_file_under_one_name_twice_is_listed_twice() {
+        let mut fs = mounted();
+        let song = create(&mut fs, ROOT, "song", FileKind::File);
+        write(&mut fs, song, b"la");
+        fs.link_name(song, ROOT, "song".as_ref()).unwrap();
+        let duplicate = suffixed(&fs, ROOT, "song");
+        assert_eq!(
+            listed(&fs, ROOT),
+            [("song".to_string(), song), (duplicate.clone(), song)]
+        );
+
+        fs.unlink_name(ROOT, duplicate.as_ref()).unwrap();
+        assert_eq!(listed(&fs, ROOT), [("song".to_string(), song)]);
+        assert_eq!(fs.get_inode(song).unwrap().attr().hardlinks, 1);
+        assert_eq!(fs.unlink_name(ROOT, duplicate.as_ref()), Err(ENOENT));
+    }
+}
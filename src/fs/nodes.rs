@@ -1,27 +1,36 @@
-use std::{cmp::Ordering, collections::BTreeSet, ffi::OsString, fmt::Display};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io::{self, Read},
+    path::PathBuf,
+};
 
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 use uuid::Uuid;
 
-use super::defs::{FileKind, Hash256, HashCalculate, InodeAttributes};
+use super::defs::{
+    FileKey, FileKind, Hash256, HashCalculate, InodeAttributes, BLOCK_SIZE, SHORT_ID_LEN,
+};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct FileNode {
     pub hash: Hash256,
     pub file_attr: InodeAttributes,
-    pub back_links: Vec<NameNode>,
+    pub back_links: Vec<Uuid>,
 }
 
 impl PartialEq for FileNode {
     fn eq(&self, other: &Self) -> bool {
-        self.hash == other.hash
+        self.key() == other.key()
     }
 }
 impl Eq for FileNode {}
 impl Ord for FileNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.hash.cmp(&other.hash)
+        self.key().cmp(&other.key())
     }
 }
 impl PartialOrd for FileNode {
@@ -32,8 +41,10 @@ impl PartialOrd for FileNode {
 
 impl FileNode {
     pub fn new(hasher: &mut Sha3_256, ino: u64, attr: Option<InodeAttributes>) -> Self {
-        Self {
-            hash: hasher.calculate_hash(),
+        let mut f = Self {
+            hash: Hash256 {
+                code: String::new(),
+            },
             file_attr: match attr {
                 Some(mut x) => {
                     x.inode = ino;
@@ -42,31 +53,54 @@ impl FileNode {
                 None => InodeAttributes::new_file_attr(ino, FileKind::File, 0o644),
             },
             back_links: Vec::new(),
+        };
+        f.calculate_hashes(hasher, &mut io::empty()).unwrap();
+
+        f
+    }
+
+    pub fn key(&self) -> FileKey {
+        FileKey {
+            hash: self.hash.clone(),
+            ino: self.file_attr.inode,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn calculate_hashes(&mut self, hasher: &mut Sha3_256) {
+    /// Recalculates the hash of the file from its content.
+    pub fn calculate_hashes(
+        &mut self,
+        hasher: &mut Sha3_256,
+        content: &mut impl Read,
+    ) -> io::Result<()> {
         // TODO: Calculate hash of the block of file
-        hasher.update(b"abc");
+        let mut buffer = [0; BLOCK_SIZE as usize];
+        loop {
+            match content.read(&mut buffer)? {
+                0 => break,
+                n => hasher.update(&buffer[..n]),
+            }
+        }
 
         self.hash = hasher.calculate_hash();
+        Ok(())
     }
 }
 
 impl Display for FileNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FileNode: {}", self.hash)
+        write!(f, "FileNode: {}", self.key())
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TagNode {
     // TODO: links to files
     pub id: Uuid,
     pub dir_attr: InodeAttributes,
     pub back_links: Vec<Uuid>,
     pub dir_links: BTreeSet<Uuid>,
+    // The same links by name, so looking one up doesn't need to read every name node
+    pub names: BTreeMap<OsString, BTreeSet<Uuid>>,
 }
 
 impl PartialEq for TagNode {
@@ -99,11 +133,31 @@ impl TagNode {
             },
             back_links: Vec::new(),
             dir_links: BTreeSet::new(),
+            names: BTreeMap::new(),
         }
     }
 
     pub fn add_file(&mut self, name_node: &NameNode) {
         self.dir_links.insert(name_node.id);
+        self.names
+            .entry(name_node.name.clone())
+            .or_default()
+            .insert(name_node.id);
+    }
+
+    pub fn remove_file(&mut self, name_node: &NameNode) {
+        self.dir_links.remove(&name_node.id);
+        if let Some(ids) = self.names.get_mut(&name_node.name) {
+            ids.remove(&name_node.id);
+            if ids.is_empty() {
+                self.names.remove(&name_node.name);
+            }
+        }
+    }
+
+    /// Ids of the name nodes in the tag that go by the name, in the same order as `dir_links`.
+    pub fn named(&self, name: &OsStr) -> impl Iterator<Item = &Uuid> {
+        self.names.get(name).into_iter().flatten()
     }
 }
 
@@ -113,32 +167,135 @@ impl Display for TagNode {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SymlinkNode {
+    pub id: Uuid,
+    pub target: PathBuf,
+    pub link_attr: InodeAttributes,
+    pub back_links: Vec<Uuid>,
+}
+
+impl PartialEq for SymlinkNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for SymlinkNode {}
+impl Ord for SymlinkNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+impl PartialOrd for SymlinkNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SymlinkNode {
+    pub fn new(ino: u64, target: PathBuf, attr: Option<InodeAttributes>) -> Self {
+        let mut link_attr = match attr {
+            Some(mut x) => {
+                x.inode = ino;
+                x
+            }
+            None => InodeAttributes::new_file_attr(ino, FileKind::Symlink, 0o777),
+        };
+        link_attr.size = target.as_os_str().len() as u64;
+
+        Self {
+            id: Uuid::new_v4(),
+            target,
+            link_attr,
+            back_links: Vec::new(),
+        }
+    }
+}
+
+impl Display for SymlinkNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SymlinkNode: {} -> {:?}", self.id, self.target)
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub enum INode {
     File(FileNode),
     Tag(TagNode),
+    Symlink(SymlinkNode),
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize, Deserialize, Debug)]
 pub enum Node {
-    File(Hash256),
+    File(FileKey),
     Tag(Uuid),
+    Symlink(Uuid),
 }
 
 impl INode {
     pub fn to_node(&self) -> Node {
         match self {
-            INode::File(f) => Node::File(f.hash.clone()),
+            INode::File(f) => Node::File(f.key()),
             INode::Tag(t) => Node::Tag(t.id),
+            INode::Symlink(l) => Node::Symlink(l.id),
         }
     }
+
+    pub fn attr(&self) -> &InodeAttributes {
+        match self {
+            INode::File(f) => &f.file_attr,
+            INode::Tag(t) => &t.dir_attr,
+            INode::Symlink(l) => &l.link_attr,
+        }
+    }
+
+    pub fn attr_mut(&mut self) -> &mut InodeAttributes {
+        match self {
+            INode::File(f) => &mut f.file_attr,
+            INode::Tag(t) => &mut t.dir_attr,
+            INode::Symlink(l) => &mut l.link_attr,
+        }
+    }
+
+    pub fn into_attr(self) -> InodeAttributes {
+        match self {
+            INode::File(f) => f.file_attr,
+            INode::Tag(t) => t.dir_attr,
+            INode::Symlink(l) => l.link_attr,
+        }
+    }
+
+    pub fn back_links(&self) -> &Vec<Uuid> {
+        match self {
+            INode::File(f) => &f.back_links,
+            INode::Tag(t) => &t.back_links,
+            INode::Symlink(l) => &l.back_links,
+        }
+    }
+
+    pub fn back_links_mut(&mut self) -> &mut Vec<Uuid> {
+        match self {
+            INode::File(f) => &mut f.back_links,
+            INode::Tag(t) => &mut t.back_links,
+            INode::Symlink(l) => &mut l.back_links,
+        }
+    }
+
+    pub fn add_back_link(&mut self, name_node: &NameNode) {
+        self.back_links_mut().push(name_node.id);
+    }
+
+    pub fn remove_back_link(&mut self, name_node: &NameNode) {
+        self.back_links_mut().retain(|id| *id != name_node.id);
+    }
 }
 
 impl Display for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Node::File(hash) => write!(f, "Node::File({:?})", hash.code),
+            Node::File(key) => write!(f, "Node::File({:?})", key.to_string()),
             Node::Tag(id) => write!(f, "Node::Tag({})", id),
+            Node::Symlink(id) => write!(f, "Node::Symlink({})", id),
         }
     }
 }
@@ -183,6 +340,13 @@ impl NameNode {
 
         n
     }
+
+    // Unlike the node it links to, the name keeps its id for good
+    pub fn short_id(&self) -> String {
+        let mut id = self.id.to_simple().to_string();
+        id.truncate(SHORT_ID_LEN);
+        id
+    }
 }
 
 impl Display for NameNode {
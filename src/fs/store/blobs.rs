@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::fs::defs::FileKey;
+
+/// File contents as plain files named by the key of their FileNode, shared by the stores that
+/// live in a directory.
+///
+/// Renames and removals only happen for real once the store has committed the nodes that go
+/// with them, until then the content stays where the committed nodes expect it.
+pub struct BlobDir {
+    dir: PathBuf,
+    // Keys that content has been renamed to, along with the one it's still stored under
+    renamed: RefCell<BTreeMap<FileKey, FileKey>>,
+    // Keys content is stored under that are gone as far as anybody can tell
+    removed: RefCell<BTreeSet<FileKey>>,
+}
+
+impl BlobDir {
+    pub fn new(dir: &Path) -> Self {
+        create_dir_all(dir).unwrap();
+        Self {
+            dir: dir.canonicalize().unwrap(),
+            renamed: RefCell::new(BTreeMap::new()),
+            removed: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    // Where the content going by a key is stored, if it's still around
+    fn stored(&self, key: &FileKey) -> Option<FileKey> {
+        let renamed = self.renamed.borrow();
+        let stored = renamed.get(key).unwrap_or(key);
+        // Content that's been renamed away doesn't go by its old key anymore
+        if self.removed.borrow().contains(stored)
+            || (!renamed.contains_key(key) && renamed.values().any(|from| from == key))
+        {
+            return None;
+        }
+
+        Some(stored.clone())
+    }
+
+    fn path(&self, key: &FileKey) -> io::Result<PathBuf> {
+        self.stored(key)
+            .map(|stored| self.dir.join(stored.to_string()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such blob"))
+    }
+
+    pub fn create(&self, key: &FileKey) -> io::Result<()> {
+        self.renamed.borrow_mut().remove(key);
+        self.removed.borrow_mut().remove(key);
+        File::create(self.path(key)?).map(|_| ())
+    }
+
+    pub fn read(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let file = File::open(self.path(key)?)?;
+        let read_size = file
+            .metadata()?
+            .len()
+            .saturating_sub(offset)
+            .min(size as u64);
+
+        let mut buffer = vec![0; read_size as usize];
+        file.read_exact_at(&mut buffer, offset)?;
+        Ok(buffer)
+    }
+
+    pub fn write(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let file = OpenOptions::new().write(true).open(self.path(key)?)?;
+        file.write_all_at(data, offset)?;
+        Ok(file.metadata()?.len())
+    }
+
+    pub fn truncate(&self, key: &FileKey, size: u64) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path(key)?)?
+            .set_len(size)
+    }
+
+    pub fn size(&self, key: &FileKey) -> Option<u64> {
+        self.path(key).ok()?.metadata().map(|m| m.len()).ok()
+    }
+
+    pub fn reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(BufReader::new(File::open(self.path(key)?)?)))
+    }
+
+    pub fn rename(&self, from: &FileKey, to: &FileKey) -> io::Result<()> {
+        if !self.path(from)?.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such blob"));
+        }
+
+        let mut renamed = self.renamed.borrow_mut();
+        let stored = renamed.remove(from).unwrap_or_else(|| from.clone());
+        renamed.insert(to.clone(), stored);
+        // Whatever is stored under the new key is replaced by the rename itself
+        self.removed.borrow_mut().remove(to);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &FileKey) -> io::Result<()> {
+        if let Some(stored) = self.stored(key) {
+            self.renamed.borrow_mut().remove(key);
+            self.removed.borrow_mut().insert(stored);
+        }
+        Ok(())
+    }
+
+    /// Keys of all the content there is, going by the new key where it's been renamed.
+    pub fn list(&self) -> Vec<FileKey> {
+        let mut keys: BTreeSet<FileKey> = self
+            .dir
+            .read_dir()
+            .unwrap()
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        for (to, stored) in self.renamed.borrow().iter() {
+            keys.remove(stored);
+            keys.insert(to.clone());
+        }
+        for stored in self.removed.borrow().iter() {
+            keys.remove(stored);
+        }
+
+        keys.into_iter().collect()
+    }
+
+    /// Renames that are yet to happen, as `(from, to)`.
+    pub fn pending_renames(&self) -> Vec<(FileKey, FileKey)> {
+        self.renamed
+            .borrow()
+            .iter()
+            .map(|(to, from)| (from.clone(), to.clone()))
+            .collect()
+    }
+
+    /// Carries out renames for real. The ones that were done already are skipped, so they can be
+    /// done over after a crash.
+    pub fn apply_renames(&self, renames: &[(FileKey, FileKey)]) -> io::Result<()> {
+        for (from, to) in renames {
+            let from_path = self.dir.join(from.to_string());
+            if from_path.exists() {
+                rename(from_path, self.dir.join(to.to_string()))?;
+            }
+            self.renamed.borrow_mut().remove(to);
+        }
+
+        Ok(())
+    }
+
+    /// Keys of the content that is yet to be removed.
+    pub fn pending_removals(&self) -> Vec<FileKey> {
+        self.removed.borrow().iter().cloned().collect()
+    }
+
+    /// Removes content for real, skipping what's gone already.
+    pub fn apply_removals(&self, keys: &[FileKey]) -> io::Result<()> {
+        for key in keys {
+            let path = self.dir.join(key.to_string());
+            if path.exists() {
+                remove_file(path)?;
+            }
+            self.removed.borrow_mut().remove(key);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    fn key(s: &str) -> FileKey {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn removals_and_renames_wait_for_the_commit() {
+        let dir = std::env::temp_dir().join(format!("tagfs-blobs-{}", std::process::id()));
+        let blobs = BlobDir::new(&dir);
+        let (a, b, c) = (key("aa-2"), key("bb-2"), key("cc-3"));
+        blobs.create(&a).unwrap();
+        blobs.write(&a, 0, b"old").unwrap();
+        blobs.create(&c).unwrap();
+
+        blobs.rename(&a, &b).unwrap();
+        blobs.remove(&c).unwrap();
+        assert_eq!(blobs.list(), std::slice::from_ref(&b));
+        assert_eq!(blobs.read(&b, 0, 16).unwrap(), b"old");
+        assert!(blobs.size(&a).is_none());
+        assert!(blobs.size(&c).is_none());
+        // What's committed is still on the disk as it was
+        assert!(dir.join(a.to_string()).exists());
+        assert!(dir.join(c.to_string()).exists());
+
+        blobs.apply_renames(&blobs.pending_renames()).unwrap();
+        blobs.apply_removals(&blobs.pending_removals()).unwrap();
+        assert!(!dir.join(a.to_string()).exists());
+        assert!(dir.join(b.to_string()).exists());
+        assert!(!dir.join(c.to_string()).exists());
+        assert_eq!(blobs.list(), [b]);
+
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::blobs::BlobDir;
+use super::NodeStore;
+use crate::fs::defs::{rewrite_symlink, FileKey, Superblock};
+use crate::fs::nodes::{INode, NameNode, Node};
+
+/// Every node in a file of its own, laid out as
+///
+/// - `inodes/<ino>`: symlink to the node with that inode number
+/// - `filenodes/<hash>-<ino>`, `tagnodes/<id>`, `symlinknodes/<id>`: the nodes
+/// - `namenodes/<name>`: ids of the name nodes going by a name
+/// - `namenodes_id/<id>`: the name nodes
+/// - `blobs/<hash>-<ino>`: content of the file under the same key in `filenodes/`
+/// - `superblock`
+/// - `journal`: changes that are being committed
+/// - `dir.lock`: locked while the store is open
+///
+/// Changes are held back until they're committed, when they're written to the journal first.
+/// Only once it's complete the changes are made to the files themselves, a journal that is
+/// still around when the store is opened again is made up for.
+pub struct DirStore {
+    data_dir: PathBuf,
+    _lock: File,
+    blobs: BlobDir,
+    // Changes since the last commit by the path they're going to be made to
+    pending: RefCell<BTreeMap<PathBuf, Change>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum Change {
+    Write(Vec<u8>),
+    // The inode links point at their nodes
+    Link(PathBuf),
+    Remove,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    // Paths are relative to the data directory
+    changes: Vec<(PathBuf, Change)>,
+    blob_renames: Vec<(FileKey, FileKey)>,
+    blob_removals: Vec<FileKey>,
+}
+
+impl DirStore {
+    pub fn new(data_dir: &Path) -> io::Result<Self> {
+        let lock = super::lock_data_dir(data_dir, "dir")?;
+        for subdir in [
+            "inodes",
+            "namenodes",
+            "namenodes_id",
+            "filenodes",
+            "tagnodes",
+            "symlinknodes",
+        ] {
+            create_dir_all(data_dir.join(subdir))?;
+        }
+
+        let store = Self {
+            // Keep working if the process changes its directory later on
+            data_dir: data_dir.canonicalize()?,
+            _lock: lock,
+            blobs: BlobDir::new(&data_dir.join("blobs")),
+            pending: RefCell::new(BTreeMap::new()),
+        };
+        store.recover()?;
+
+        Ok(store)
+    }
+
+    // Finishes off a commit that was cut short
+    fn recover(&self) -> io::Result<()> {
+        let path = self.data_dir.join("journal");
+        let journal: Journal = match File::open(&path) {
+            Ok(file) => match bincode::deserialize_from(BufReader::new(file)) {
+                Ok(journal) => journal,
+                Err(e) => {
+                    error!("DirStore::recover | journal is corrupted, dropping it: {e}");
+                    return remove_file(&path);
+                }
+            },
+            Err(_) => return Ok(()),
+        };
+
+        debug!("DirStore::recover | {} changes", journal.changes.len());
+        self.apply(&journal)?;
+        remove_file(path)
+    }
+
+    fn apply(&self, journal: &Journal) -> io::Result<()> {
+        for (path, change) in &journal.changes {
+            let path = self.data_dir.join(path);
+            match change {
+                Change::Write(data) => {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)?;
+                    file.write_all(data)?;
+                }
+                Change::Link(target) => rewrite_symlink(target.clone(), path),
+                Change::Remove => {
+                    if path.symlink_metadata().is_ok() {
+                        remove_file(path)?;
+                    }
+                }
+            }
+        }
+        self.blobs.apply_renames(&journal.blob_renames)?;
+        self.blobs.apply_removals(&journal.blob_removals)?;
+
+        // Everything has to be on the disk before the journal can go
+        let dir = File::open(&self.data_dir)?;
+        if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: &Path) -> Option<T> {
+        match self.pending.borrow().get(path) {
+            Some(Change::Write(data)) => return Some(bincode::deserialize(data).unwrap()),
+            Some(_) => return None,
+            None => (),
+        }
+
+        let file = File::open(path).ok()?;
+        Some(bincode::deserialize_from(BufReader::new(file)).unwrap())
+    }
+
+    fn write<T: Serialize + ?Sized>(&self, path: &Path, value: &T) {
+        let data = bincode::serialize(value).unwrap();
+        self.pending
+            .borrow_mut()
+            .insert(path.to_path_buf(), Change::Write(data));
+    }
+
+    fn remove(&self, path: &Path) {
+        self.pending
+            .borrow_mut()
+            .insert(path.to_path_buf(), Change::Remove);
+    }
+
+    fn link(&self, target: PathBuf, path: &Path) {
+        self.pending
+            .borrow_mut()
+            .insert(path.to_path_buf(), Change::Link(target));
+    }
+
+    fn read_link(&self, path: &Path) -> Option<PathBuf> {
+        match self.pending.borrow().get(path) {
+            Some(Change::Link(target)) => Some(target.clone()),
+            Some(_) => None,
+            None => path.read_link().ok(),
+        }
+    }
+
+    fn node_path(&self, node: &Node) -> PathBuf {
+        match node {
+            Node::File(key) => self.data_dir.join("filenodes").join(key.to_string()),
+            Node::Tag(id) => self.data_dir.join("tagnodes").join(id.to_string()),
+            Node::Symlink(id) => self.data_dir.join("symlinknodes").join(id.to_string()),
+        }
+    }
+
+    fn ino_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join("inodes").join(ino.to_string())
+    }
+
+    fn list(&self, subdir: &str) -> Vec<OsString> {
+        let dir = self.data_dir.join(subdir);
+        let mut names: BTreeSet<OsString> = dir
+            .read_dir()
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name())
+            .collect();
+
+        for (path, change) in self.pending.borrow().iter() {
+            if path.parent() != Some(&dir) {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_os_string();
+            match change {
+                Change::Remove => names.remove(&name),
+                _ => names.insert(name),
+            };
+        }
+
+        names.into_iter().collect()
+    }
+
+    fn list_parsed<T: std::str::FromStr>(&self, subdir: &str) -> Vec<T> {
+        self.list(subdir)
+            .iter()
+            .filter_map(|name| name.to_str()?.parse().ok())
+            .collect()
+    }
+}
+
+impl NodeStore for DirStore {
+    fn read_superblock(&self) -> io::Result<Option<Superblock>> {
+        let path = self.data_dir.join("superblock");
+        if let Some(Change::Write(data)) = self.pending.borrow().get(&path) {
+            return Ok(Some(bincode::deserialize(data).unwrap()));
+        }
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        bincode::deserialize_from(BufReader::new(file))
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_superblock(&self, superblock: &Superblock) -> io::Result<()> {
+        self.write(&self.data_dir.join("superblock"), superblock);
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.list("inodes").is_empty()
+    }
+
+    fn node_of(&self, ino: u64) -> Option<Node> {
+        let path = self.read_link(&self.ino_path(ino))?;
+        let name = path.file_name()?.to_string_lossy();
+        let parent = path.parent()?;
+        if parent.ends_with("tagnodes") {
+            Some(Node::Tag(name.parse().ok()?))
+        } else if parent.ends_with("filenodes") {
+            Some(Node::File(name.parse().ok()?))
+        } else if parent.ends_with("symlinknodes") {
+            Some(Node::Symlink(name.parse().ok()?))
+        } else {
+            None
+        }
+    }
+
+    fn load_node(&self, node: &Node) -> Option<INode> {
+        let path = self.node_path(node);
+        match node {
+            Node::File(_) => self.read(&path).map(INode::File),
+            Node::Tag(_) => self.read(&path).map(INode::Tag),
+            Node::Symlink(_) => self.read(&path).map(INode::Symlink),
+        }
+    }
+
+    fn store_node(&self, inode: &INode) -> io::Result<()> {
+        debug!("\tstore_node | {}", inode.to_node());
+
+        let path = self.node_path(&inode.to_node());
+        match inode {
+            INode::File(f) => self.write(&path, f),
+            INode::Tag(t) => self.write(&path, t),
+            INode::Symlink(l) => self.write(&path, l),
+        }
+        self.link(path, &self.ino_path(inode.attr().inode));
+        Ok(())
+    }
+
+    fn remove_node(&self, inode: &INode) -> io::Result<()> {
+        debug!("\tremove_node | {}", inode.to_node());
+
+        let path = self.node_path(&inode.to_node());
+        let ino_path = self.ino_path(inode.attr().inode);
+        // A rehashed file has its inode number pointing at the new key already
+        if self.read_link(&ino_path) == Some(path.clone()) {
+            self.remove(&ino_path);
+        }
+        self.remove(&path);
+        Ok(())
+    }
+
+    fn list_tags(&self) -> Vec<Uuid> {
+        self.list_parsed("tagnodes")
+    }
+
+    fn list_symlinks(&self) -> Vec<Uuid> {
+        self.list_parsed("symlinknodes")
+    }
+
+    fn list_files(&self) -> Vec<FileKey> {
+        self.list_parsed("filenodes")
+    }
+
+    fn list_inos(&self) -> Vec<u64> {
+        self.list_parsed("inodes")
+    }
+
+    fn forget_ino(&self, ino: u64) -> io::Result<()> {
+        self.remove(&self.ino_path(ino));
+        Ok(())
+    }
+
+    fn load_name_node(&self, id: &Uuid) -> Option<NameNode> {
+        self.read(&self.data_dir.join("namenodes_id").join(id.to_string()))
+    }
+
+    fn store_name_node(&self, name_node: &NameNode) -> io::Result<()> {
+        debug!("\tstore_name_node | {name_node}");
+        self.write(
+            &self
+                .data_dir
+                .join("namenodes_id")
+                .join(name_node.id.to_string()),
+            name_node,
+        );
+        Ok(())
+    }
+
+    fn remove_name_node(&self, id: &Uuid) -> io::Result<()> {
+        self.remove(&self.data_dir.join("namenodes_id").join(id.to_string()));
+        Ok(())
+    }
+
+    fn list_name_nodes(&self) -> Vec<Uuid> {
+        self.list_parsed("namenodes_id")
+    }
+
+    fn load_name_set(&self, name: &OsStr) -> BTreeSet<Uuid> {
+        self.read(&self.data_dir.join("namenodes").join(name))
+            .unwrap_or_default()
+    }
+
+    fn store_name_set(&self, name: &OsStr, ids: &BTreeSet<Uuid>) -> io::Result<()> {
+        let path = self.data_dir.join("namenodes").join(name);
+        if ids.is_empty() {
+            self.remove(&path);
+        } else {
+            self.write(&path, ids);
+        }
+        Ok(())
+    }
+
+    fn list_names(&self) -> Vec<OsString> {
+        self.list("namenodes")
+    }
+
+    fn create_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.blobs.create(key)
+    }
+
+    fn read_blob(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.blobs.read(key, offset, size)
+    }
+
+    fn write_blob(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64> {
+        self.blobs.write(key, offset, data)
+    }
+
+    fn truncate_blob(&self, key: &FileKey, size: u64) -> io::Result<()> {
+        self.blobs.truncate(key, size)
+    }
+
+    fn blob_size(&self, key: &FileKey) -> Option<u64> {
+        self.blobs.size(key)
+    }
+
+    fn blob_reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>> {
+        self.blobs.reader(key)
+    }
+
+    fn rename_blob(&self, from: &FileKey, to: &FileKey) -> io::Result<()> {
+        self.blobs.rename(from, to)
+    }
+
+    fn remove_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.blobs.remove(key)
+    }
+
+    fn list_blobs(&self) -> Vec<FileKey> {
+        self.blobs.list()
+    }
+
+    fn commit(&self) -> io::Result<()> {
+        // A journal that failed to be applied last time has to go first, it'd be overwritten
+        if self.data_dir.join("journal").exists() {
+            self.recover()?;
+        }
+
+        let blob_renames = self.blobs.pending_renames();
+        let blob_removals = self.blobs.pending_removals();
+        if self.pending.borrow().is_empty() && blob_renames.is_empty() && blob_removals.is_empty() {
+            return Ok(());
+        }
+        debug!("\tcommit | {} changes", self.pending.borrow().len());
+
+        let journal = Journal {
+            changes: self
+                .pending
+                .borrow()
+                .iter()
+                .map(|(path, change)| {
+                    (
+                        path.strip_prefix(&self.data_dir).unwrap().to_path_buf(),
+                        change.clone(),
+                    )
+                })
+                .collect(),
+            blob_renames,
+            blob_removals,
+        };
+
+        // The journal only shows up under its name once all of it is on the disk
+        let tmp_path = self.data_dir.join("journal.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bincode::serialize(&journal).unwrap())?;
+        file.sync_all()?;
+        rename(&tmp_path, self.data_dir.join("journal"))?;
+        File::open(&self.data_dir)?.sync_all()?;
+
+        // The journal has it from here on, if applying it fails it's done over later
+        self.pending.borrow_mut().clear();
+        self.apply(&journal)?;
+        remove_file(self.data_dir.join("journal"))
+    }
+}
+
+impl Drop for DirStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("DirStore::commit | {e}");
+        }
+    }
+}
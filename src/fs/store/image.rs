@@ -0,0 +1,954 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use libc::{ENOENT, ENOSPC};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use uuid::Uuid;
+
+use super::NodeStore;
+use crate::fs::defs::{FileKey, Superblock};
+use crate::fs::nodes::{INode, NameNode, Node};
+
+const IMAGE_BLOCK_SIZE: u64 = 4096;
+const IMAGE_MAGIC: [u8; 8] = *b"TAGFSIMG";
+// Bumped whenever the layout of the image changes
+const IMAGE_VERSION: u32 = 2;
+// The headers come first, followed by the two free-space maps, the rest holds data
+const HEADER_SLOTS: u64 = 2;
+const MIN_DATA_BLOCKS: u64 = 16;
+// As many as fit in a block along with the length and the next block
+const EXTENTS_PER_BLOCK: usize = 250;
+
+/// A run of consecutive blocks.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Extent {
+    start: u64,
+    len: u64,
+}
+
+/// Where everything else is to be found. There are two slots for it, commits alternate between
+/// them so that the previous one is still intact if writing the next one is cut short.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    block_count: u64,
+    generation: u64,
+    // Blocks taken by each of the two free-space maps, the one in use goes with the generation
+    bitmap_blocks: u64,
+    // First block of the list of extents holding the serialized `Tables`, and their length in
+    // bytes
+    metadata_list: Option<u64>,
+    metadata_len: u64,
+}
+
+/// Part of the list of extents that hold the tables, one per block, chained to the next one.
+#[derive(Serialize, Deserialize)]
+struct ExtentListBlock {
+    extents: Vec<Extent>,
+    next: Option<u64>,
+}
+
+impl Header {
+    fn slot(&self) -> u64 {
+        self.generation % HEADER_SLOTS
+    }
+
+    fn bitmap_start(&self, slot: u64) -> u64 {
+        HEADER_SLOTS + slot * self.bitmap_blocks
+    }
+
+    fn reserved_blocks(&self) -> u64 {
+        HEADER_SLOTS + 2 * self.bitmap_blocks
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Blob {
+    len: u64,
+    extents: Vec<Extent>,
+}
+
+impl Blob {
+    // Position on the device of every part of `len` bytes starting at `offset`, along with the
+    // range of those bytes it holds
+    fn locate(&self, offset: u64, len: u64) -> Vec<(u64, std::ops::Range<usize>)> {
+        let mut parts = Vec::new();
+        let (mut extent_offset, mut done) = (0, 0);
+        for extent in &self.extents {
+            let extent_len = extent.len * IMAGE_BLOCK_SIZE;
+            let pos = offset + done;
+            if done < len && pos < extent_offset + extent_len {
+                let inner = pos - extent_offset;
+                let n = (extent_len - inner).min(len - done);
+                parts.push((
+                    extent.start * IMAGE_BLOCK_SIZE + inner,
+                    done as usize..(done + n) as usize,
+                ));
+                done += n;
+            }
+            extent_offset += extent_len;
+        }
+
+        parts
+    }
+
+    fn capacity(&self) -> u64 {
+        self.extents.iter().map(|e| e.len).sum::<u64>() * IMAGE_BLOCK_SIZE
+    }
+}
+
+/// Every node of the store, written out as a whole on each commit.
+#[derive(Serialize, Deserialize, Default)]
+struct Tables {
+    superblock: Option<Superblock>,
+    nodes: BTreeMap<Node, INode>,
+    inos: BTreeMap<u64, Node>,
+    name_nodes: BTreeMap<Uuid, NameNode>,
+    name_sets: BTreeMap<OsString, BTreeSet<Uuid>>,
+    blobs: BTreeMap<FileKey, Blob>,
+}
+
+struct Image {
+    file: File,
+    header: Header,
+    // Where the committed tables are, along with the blocks listing them
+    metadata: Vec<Extent>,
+    metadata_list: Vec<u64>,
+    // One bit per block, set for the ones in use
+    used: Vec<u8>,
+    // Set for the blocks taken since the last commit, nothing committed refers to them
+    fresh: Vec<u8>,
+    // Blocks let go of since the last commit, the committed tables might still refer to them
+    freed: Vec<Extent>,
+    tables: Tables,
+    dirty: bool,
+}
+
+/// Keeps the whole store in a single file or block device, formatted by `mkfs`.
+///
+/// The node tables live in memory and are written to newly allocated blocks on every commit,
+/// only then the header is switched over to them. File contents are written in place.
+pub struct ImageStore {
+    image: RefCell<Image>,
+}
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(ENOENT)
+}
+
+fn blocks_for(bytes: u64) -> u64 {
+    bytes.div_ceil(IMAGE_BLOCK_SIZE)
+}
+
+fn is_used(used: &[u8], block: u64) -> bool {
+    used[(block / 8) as usize] & (1 << (block % 8)) != 0
+}
+
+fn set_used(used: &mut [u8], extent: &Extent, value: bool) {
+    for block in extent.start..extent.start + extent.len {
+        if value {
+            used[(block / 8) as usize] |= 1 << (block % 8);
+        } else {
+            used[(block / 8) as usize] &= !(1 << (block % 8));
+        }
+    }
+}
+
+fn write_header(file: &File, header: &Header) -> io::Result<()> {
+    let data = bincode::serialize(header).unwrap();
+    if data.len() as u64 + 36 > IMAGE_BLOCK_SIZE {
+        return Err(io::Error::from_raw_os_error(ENOSPC));
+    }
+
+    // The checksum tells a torn header apart from a whole one
+    let mut block = Sha3_256::digest(&data).to_vec();
+    block.extend((data.len() as u32).to_le_bytes());
+    block.extend(data);
+    block.resize(IMAGE_BLOCK_SIZE as usize, 0);
+    file.write_all_at(&block, header.slot() * IMAGE_BLOCK_SIZE)
+}
+
+// Follows the chain of blocks listing the extents of the tables, returning the extents along
+// with the blocks of the chain
+fn read_extent_list(file: &File, header: &Header) -> io::Result<(Vec<Extent>, Vec<u64>)> {
+    let (mut extents, mut blocks) = (Vec::new(), Vec::new());
+    let mut next = header.metadata_list;
+    while let Some(block) = next {
+        // A chain that runs in circles or out of the image is a broken one
+        if blocks.len() as u64 >= header.block_count || block >= header.block_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the metadata extent list is broken",
+            ));
+        }
+
+        let mut data = vec![0; IMAGE_BLOCK_SIZE as usize];
+        file.read_exact_at(&mut data, block * IMAGE_BLOCK_SIZE)?;
+        let list: ExtentListBlock = bincode::deserialize(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        extents.extend(list.extents);
+        blocks.push(block);
+        next = list.next;
+    }
+
+    Ok((extents, blocks))
+}
+
+fn write_extent_list(file: &File, extents: &[Extent], blocks: &[u64]) -> io::Result<()> {
+    for (i, (chunk, block)) in extents.chunks(EXTENTS_PER_BLOCK).zip(blocks).enumerate() {
+        let list = ExtentListBlock {
+            extents: chunk.to_vec(),
+            next: blocks.get(i + 1).copied(),
+        };
+        let mut data = bincode::serialize(&list).unwrap();
+        data.resize(IMAGE_BLOCK_SIZE as usize, 0);
+        file.write_all_at(&data, block * IMAGE_BLOCK_SIZE)?;
+    }
+
+    Ok(())
+}
+
+fn read_header(file: &File, slot: u64) -> Option<Header> {
+    let mut block = vec![0; IMAGE_BLOCK_SIZE as usize];
+    file.read_exact_at(&mut block, slot * IMAGE_BLOCK_SIZE)
+        .ok()?;
+
+    let len = u32::from_le_bytes(block[32..36].try_into().unwrap()) as usize;
+    let data = block.get(36..36 + len)?;
+    if Sha3_256::digest(data).as_slice() != &block[..32] {
+        return None;
+    }
+    let header: Header = bincode::deserialize(data).ok()?;
+    (header.magic == IMAGE_MAGIC && header.slot() == slot).then_some(header)
+}
+
+/// Formats a file or block device as an image holding nothing but the root tag. Files are grown
+/// (or shrunk) to `size`, without one the current size is used.
+pub fn mkfs(path: &Path, size: Option<u64>) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Not from under a mounted image
+    super::lock(&file)?;
+    if let Some(size) = size {
+        if file.metadata()?.is_file() {
+            file.set_len(size)?;
+        }
+    }
+    // Block devices only tell their size this way
+    let block_count = file.seek(SeekFrom::End(0))? / IMAGE_BLOCK_SIZE;
+
+    let bitmap_blocks = blocks_for(block_count.div_ceil(8));
+    let header = Header {
+        magic: IMAGE_MAGIC,
+        version: IMAGE_VERSION,
+        block_count,
+        generation: 0,
+        bitmap_blocks,
+        metadata_list: None,
+        metadata_len: 0,
+    };
+    if block_count < header.reserved_blocks() + MIN_DATA_BLOCKS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "an image needs at least {} bytes",
+                (header.reserved_blocks() + MIN_DATA_BLOCKS) * IMAGE_BLOCK_SIZE
+            ),
+        ));
+    }
+
+    let mut used = vec![0; (bitmap_blocks * IMAGE_BLOCK_SIZE) as usize];
+    let reserved = Extent {
+        start: 0,
+        len: header.reserved_blocks(),
+    };
+    set_used(&mut used, &reserved, true);
+    file.write_all_at(&used, header.bitmap_start(header.slot()) * IMAGE_BLOCK_SIZE)?;
+
+    // Whatever was in the other slot before mustn't be taken for a newer header
+    file.write_all_at(&vec![0; IMAGE_BLOCK_SIZE as usize], IMAGE_BLOCK_SIZE)?;
+    write_header(&file, &header)?;
+    file.sync_all()?;
+    drop(file);
+
+    super::format(&ImageStore::open(path)?)
+}
+
+impl ImageStore {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        super::lock(&file)?;
+        let header = (0..HEADER_SLOTS)
+            .filter_map(|slot| read_header(&file, slot))
+            .max_by_key(|header| header.generation)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a tag_fs image"))?;
+        if header.version != IMAGE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "image has version {}, expected {IMAGE_VERSION}",
+                    header.version
+                ),
+            ));
+        }
+        debug!("ImageStore::open | {header:?}");
+
+        let mut used = vec![0; (header.bitmap_blocks * IMAGE_BLOCK_SIZE) as usize];
+        file.read_exact_at(
+            &mut used,
+            header.bitmap_start(header.slot()) * IMAGE_BLOCK_SIZE,
+        )?;
+
+        let (extents, metadata_list) = read_extent_list(&file, &header)?;
+        let metadata = Blob {
+            len: header.metadata_len,
+            extents,
+        };
+        let tables = if metadata.len == 0 {
+            Tables::default()
+        } else {
+            let mut data = vec![0; metadata.len as usize];
+            for (pos, range) in metadata.locate(0, metadata.len) {
+                file.read_exact_at(&mut data[range], pos)?;
+            }
+            bincode::deserialize(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        };
+
+        Ok(Self {
+            image: RefCell::new(Image {
+                file,
+                header,
+                metadata: metadata.extents,
+                metadata_list,
+                fresh: vec![0; used.len()],
+                used,
+                freed: Vec::new(),
+                tables,
+                dirty: false,
+            }),
+        })
+    }
+}
+
+impl Image {
+    // Takes `count` free blocks, next to `near` if possible, otherwise wherever they're found
+    fn allocate(&mut self, count: u64, near: Option<u64>) -> io::Result<Vec<Extent>> {
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut left = count;
+        let start = near.unwrap_or(0).max(self.header.reserved_blocks());
+        let blocks = (start..self.header.block_count).chain(0..start);
+        for block in blocks {
+            if left == 0 {
+                break;
+            }
+            if is_used(&self.used, block) {
+                continue;
+            }
+
+            match extents.last_mut() {
+                Some(last) if last.start + last.len == block => last.len += 1,
+                _ => extents.push(Extent {
+                    start: block,
+                    len: 1,
+                }),
+            }
+            left -= 1;
+        }
+
+        if left > 0 {
+            return Err(io::Error::from_raw_os_error(ENOSPC));
+        }
+        for extent in &extents {
+            set_used(&mut self.used, extent, true);
+            set_used(&mut self.fresh, extent, true);
+        }
+        Ok(extents)
+    }
+
+    // Blocks taken since the last commit are free again right away, the rest only once the
+    // tables that don't refer to them anymore are committed
+    fn free(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            for block in extent.start..extent.start + extent.len {
+                let block = Extent {
+                    start: block,
+                    len: 1,
+                };
+                if is_used(&self.fresh, block.start) {
+                    self.release(&[block]);
+                    continue;
+                }
+                match self.freed.last_mut() {
+                    Some(last) if last.start + last.len == block.start => last.len += 1,
+                    _ => self.freed.push(block),
+                }
+            }
+        }
+    }
+
+    // Hands blocks that nothing committed refers to straight back
+    fn release(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            set_used(&mut self.used, extent, false);
+            set_used(&mut self.fresh, extent, false);
+        }
+    }
+
+    fn write_zeros(&self, blob: &Blob, offset: u64, len: u64) -> io::Result<()> {
+        let zeros = vec![0; len.min(1 << 20) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(zeros.len() as u64);
+            for (pos, range) in blob.locate(offset + done, n) {
+                self.file.write_all_at(&zeros[range], pos)?;
+            }
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    // Makes room for `len` bytes in the blob, the blocks of the last extent are extended first
+    fn grow(&mut self, key: &FileKey, len: u64) -> io::Result<()> {
+        let blob = self.tables.blobs.get(key).ok_or_else(not_found)?;
+        let missing = blocks_for(len).saturating_sub(blocks_for(blob.capacity()));
+        if missing == 0 {
+            return Ok(());
+        }
+
+        // What's been freed since the last commit only comes back with the next one
+        let near = blob.extents.last().map(|e| e.start + e.len);
+        let extents = self.allocate(missing, near)?;
+        let blob = self.tables.blobs.get_mut(key).unwrap();
+        for extent in extents {
+            match blob.extents.last_mut() {
+                Some(last) if last.start + last.len == extent.start => last.len += extent.len,
+                _ => blob.extents.push(extent),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let data = bincode::serialize(&self.tables).unwrap();
+        let extents = self.allocate(blocks_for(data.len() as u64), None)?;
+        let list_blocks =
+            match self.allocate(extents.len().div_ceil(EXTENTS_PER_BLOCK) as u64, None) {
+                Ok(list_blocks) => list_blocks,
+                Err(e) => {
+                    self.release(&extents);
+                    return Err(e);
+                }
+            };
+        let metadata = Blob {
+            len: data.len() as u64,
+            extents,
+        };
+        let metadata_list: Vec<u64> = list_blocks
+            .iter()
+            .flat_map(|extent| extent.start..extent.start + extent.len)
+            .collect();
+
+        // Neither the old tables nor anything freed since are referred to by the new ones
+        let mut used = self.used.clone();
+        let old_list = self.metadata_list.iter().map(|&block| Extent {
+            start: block,
+            len: 1,
+        });
+        for extent in self
+            .freed
+            .iter()
+            .chain(&self.metadata)
+            .copied()
+            .chain(old_list)
+        {
+            set_used(&mut used, &extent, false);
+        }
+
+        let mut header = self.header.clone();
+        header.generation += 1;
+        header.metadata_list = metadata_list.first().copied();
+        header.metadata_len = metadata.len;
+        if let Err(e) = self.write_metadata(&header, &data, &metadata, &metadata_list, &used) {
+            // The header still points at the old tables, the new ones can go
+            self.release(&metadata.extents);
+            self.release(&list_blocks);
+            return Err(e);
+        }
+
+        self.header = header;
+        self.metadata = metadata.extents;
+        self.metadata_list = metadata_list;
+        self.used = used;
+        self.fresh.fill(0);
+        self.freed.clear();
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Everything but the header has to be on the disk before the header can point at it
+    fn write_metadata(
+        &self,
+        header: &Header,
+        data: &[u8],
+        metadata: &Blob,
+        metadata_list: &[u64],
+        used: &[u8],
+    ) -> io::Result<()> {
+        for (pos, range) in metadata.locate(0, metadata.len) {
+            self.file.write_all_at(&data[range], pos)?;
+        }
+        write_extent_list(&self.file, &metadata.extents, metadata_list)?;
+        self.file
+            .write_all_at(used, header.bitmap_start(header.slot()) * IMAGE_BLOCK_SIZE)?;
+        self.file.sync_data()?;
+        write_header(&self.file, header)?;
+        self.file.sync_data()
+    }
+}
+
+struct BlobReader {
+    file: File,
+    blob: Blob,
+    pos: u64,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.blob.len.saturating_sub(self.pos));
+        for (pos, range) in self.blob.locate(self.pos, n) {
+            self.file.read_exact_at(&mut buf[range], pos)?;
+        }
+        self.pos += n;
+        Ok(n as usize)
+    }
+}
+
+impl NodeStore for ImageStore {
+    fn read_superblock(&self) -> io::Result<Option<Superblock>> {
+        Ok(self.image.borrow().tables.superblock.clone())
+    }
+
+    fn write_superblock(&self, superblock: &Superblock) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        image.tables.superblock = Some(superblock.clone());
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.image.borrow().tables.nodes.is_empty()
+    }
+
+    fn node_of(&self, ino: u64) -> Option<Node> {
+        self.image.borrow().tables.inos.get(&ino).cloned()
+    }
+
+    fn load_node(&self, node: &Node) -> Option<INode> {
+        self.image.borrow().tables.nodes.get(node).cloned()
+    }
+
+    fn store_node(&self, inode: &INode) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        let tables = &mut image.tables;
+        tables.inos.insert(inode.attr().inode, inode.to_node());
+        tables.nodes.insert(inode.to_node(), inode.clone());
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn remove_node(&self, inode: &INode) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        let tables = &mut image.tables;
+        let node = inode.to_node();
+        let ino = inode.attr().inode;
+        if tables.inos.get(&ino) == Some(&node) {
+            tables.inos.remove(&ino);
+        }
+        tables.nodes.remove(&node);
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn list_tags(&self) -> Vec<Uuid> {
+        self.image
+            .borrow()
+            .tables
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::Tag(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_files(&self) -> Vec<FileKey> {
+        self.image
+            .borrow()
+            .tables
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::File(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_symlinks(&self) -> Vec<Uuid> {
+        self.image
+            .borrow()
+            .tables
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::Symlink(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_inos(&self) -> Vec<u64> {
+        self.image.borrow().tables.inos.keys().copied().collect()
+    }
+
+    fn forget_ino(&self, ino: u64) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        if image.tables.inos.remove(&ino).is_some() {
+            image.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn load_name_node(&self, id: &Uuid) -> Option<NameNode> {
+        self.image.borrow().tables.name_nodes.get(id).cloned()
+    }
+
+    fn store_name_node(&self, name_node: &NameNode) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        image
+            .tables
+            .name_nodes
+            .insert(name_node.id, name_node.clone());
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn remove_name_node(&self, id: &Uuid) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        image.tables.name_nodes.remove(id);
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn list_name_nodes(&self) -> Vec<Uuid> {
+        self.image
+            .borrow()
+            .tables
+            .name_nodes
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    fn load_name_set(&self, name: &OsStr) -> BTreeSet<Uuid> {
+        self.image
+            .borrow()
+            .tables
+            .name_sets
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn store_name_set(&self, name: &OsStr, ids: &BTreeSet<Uuid>) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        if ids.is_empty() {
+            image.tables.name_sets.remove(name);
+        } else {
+            image
+                .tables
+                .name_sets
+                .insert(name.to_os_string(), ids.clone());
+        }
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn list_names(&self) -> Vec<OsString> {
+        self.image
+            .borrow()
+            .tables
+            .name_sets
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn create_blob(&self, key: &FileKey) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        if let Some(old) = image.tables.blobs.remove(key) {
+            image.free(&old.extents);
+        }
+        image.tables.blobs.insert(
+            key.clone(),
+            Blob {
+                len: 0,
+                extents: Vec::new(),
+            },
+        );
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn read_blob(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let image = self.image.borrow();
+        let blob = image.tables.blobs.get(key).ok_or_else(not_found)?;
+        let len = blob.len.saturating_sub(offset).min(size as u64);
+
+        let mut buffer = vec![0; len as usize];
+        for (pos, range) in blob.locate(offset, len) {
+            image.file.read_exact_at(&mut buffer[range], pos)?;
+        }
+        Ok(buffer)
+    }
+
+    fn write_blob(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let mut image = self.image.borrow_mut();
+        let end = offset + data.len() as u64;
+        image.grow(key, end)?;
+
+        let blob = image.tables.blobs.get(key).unwrap().clone();
+        // Blocks that were taken over might still hold someone else's content
+        if offset > blob.len {
+            image.write_zeros(&blob, blob.len, offset - blob.len)?;
+        }
+        for (pos, range) in blob.locate(offset, data.len() as u64) {
+            image.file.write_all_at(&data[range], pos)?;
+        }
+
+        let blob = image.tables.blobs.get_mut(key).unwrap();
+        blob.len = blob.len.max(end);
+        let len = blob.len;
+        image.dirty = true;
+        Ok(len)
+    }
+
+    fn truncate_blob(&self, key: &FileKey, size: u64) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        image.grow(key, size)?;
+
+        let mut blob = image.tables.blobs.get(key).unwrap().clone();
+        if size > blob.len {
+            image.write_zeros(&blob, blob.len, size - blob.len)?;
+        }
+
+        // Blocks past the new end go back to the free ones
+        let mut keep = blocks_for(size);
+        let mut freed = Vec::new();
+        blob.extents.retain_mut(|extent| {
+            if keep >= extent.len {
+                keep -= extent.len;
+                return true;
+            }
+            freed.push(Extent {
+                start: extent.start + keep,
+                len: extent.len - keep,
+            });
+            extent.len = keep;
+            keep = 0;
+            extent.len > 0
+        });
+        blob.len = size;
+
+        image.free(&freed);
+        image.tables.blobs.insert(key.clone(), blob);
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn blob_size(&self, key: &FileKey) -> Option<u64> {
+        self.image.borrow().tables.blobs.get(key).map(|b| b.len)
+    }
+
+    fn blob_reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>> {
+        let image = self.image.borrow();
+        let blob = image.tables.blobs.get(key).ok_or_else(not_found)?;
+        Ok(Box::new(BlobReader {
+            file: image.file.try_clone()?,
+            blob: blob.clone(),
+            pos: 0,
+        }))
+    }
+
+    fn rename_blob(&self, from: &FileKey, to: &FileKey) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        let blob = image.tables.blobs.remove(from).ok_or_else(not_found)?;
+        if let Some(old) = image.tables.blobs.insert(to.clone(), blob) {
+            image.free(&old.extents);
+        }
+        image.dirty = true;
+        Ok(())
+    }
+
+    fn remove_blob(&self, key: &FileKey) -> io::Result<()> {
+        let mut image = self.image.borrow_mut();
+        if let Some(blob) = image.tables.blobs.remove(key) {
+            image.free(&blob.extents);
+            image.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn list_blobs(&self) -> Vec<FileKey> {
+        self.image.borrow().tables.blobs.keys().cloned().collect()
+    }
+
+    fn commit(&self) -> io::Result<()> {
+        self.image.borrow_mut().commit()
+    }
+}
+
+impl Drop for ImageStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("ImageStore::commit | {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn image_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tagfs-{name}-{}.img", std::process::id()))
+    }
+
+    fn key(s: &str) -> FileKey {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn formatted_images_hold_the_root() {
+        let path = image_path("format");
+        mkfs(&path, Some(1 << 20)).unwrap();
+
+        let store = ImageStore::open(&path).unwrap();
+        let superblock = store.read_superblock().unwrap().unwrap();
+        assert!(matches!(
+            store.load_node(&Node::Tag(superblock.root)),
+            Some(INode::Tag(_))
+        ));
+        assert_eq!(
+            store.node_of(fuser::FUSE_ROOT_ID),
+            Some(Node::Tag(superblock.root))
+        );
+
+        drop(store);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tables_can_be_spread_over_any_number_of_extents() {
+        let path = image_path("extents");
+        mkfs(&path, Some(8 << 20)).unwrap();
+        let store = ImageStore::open(&path).unwrap();
+
+        // Every other block is taken, so the tables end up in blocks of their own
+        {
+            let mut image = store.image.borrow_mut();
+            for block in (image.header.reserved_blocks()..image.header.block_count).step_by(2) {
+                set_used(
+                    &mut image.used,
+                    &Extent {
+                        start: block,
+                        len: 1,
+                    },
+                    true,
+                );
+            }
+        }
+        let root = store.read_superblock().unwrap().unwrap().root;
+        for i in 0..20000 {
+            let name_node = NameNode::new(format!("name {i}").into(), Node::Tag(root));
+            store.store_name_node(&name_node).unwrap();
+        }
+        store.commit().unwrap();
+        let list_blocks = store.image.borrow().metadata_list.len();
+        assert!(list_blocks > 1, "{list_blocks} blocks listing the tables");
+        drop(store);
+
+        let store = ImageStore::open(&path).unwrap();
+        assert_eq!(store.list_name_nodes().len(), 20000);
+
+        drop(store);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_blocks_taken_since_the_last_commit_come_back_before_the_next_one() {
+        let path = image_path("full");
+        mkfs(&path, Some(1 << 20)).unwrap();
+        let store = ImageStore::open(&path).unwrap();
+        let free = {
+            let image = store.image.borrow();
+            let data = image.header.reserved_blocks()..image.header.block_count;
+            data.filter(|&block| !is_used(&image.used, block)).count()
+        };
+        // Leaves room for the tables and the list of their extents to be committed
+        let half = vec![1; (free - 4) / 2 * IMAGE_BLOCK_SIZE as usize];
+
+        let (a, b) = (key("aa-2"), key("bb-3"));
+        store.create_blob(&a).unwrap();
+        store.write_blob(&a, 0, &half).unwrap();
+        store.commit().unwrap();
+        store.create_blob(&b).unwrap();
+        store
+            .write_blob(&b, 0, &half[IMAGE_BLOCK_SIZE as usize..])
+            .unwrap();
+        let full = store.write_blob(&b, half.len() as u64, &half).unwrap_err();
+        assert_eq!(full.raw_os_error(), Some(ENOSPC));
+
+        // The committed tables still have the first blob
+        store.remove_blob(&a).unwrap();
+        let full = store.write_blob(&b, half.len() as u64, &half).unwrap_err();
+        assert_eq!(full.raw_os_error(), Some(ENOSPC));
+        store.remove_blob(&b).unwrap();
+        store.create_blob(&b).unwrap();
+        store
+            .write_blob(&b, 0, &half[IMAGE_BLOCK_SIZE as usize..])
+            .unwrap();
+
+        store.commit().unwrap();
+        let len = store.blob_size(&b).unwrap();
+        store
+            .write_blob(&b, len, &half[2 * IMAGE_BLOCK_SIZE as usize..])
+            .unwrap();
+
+        drop(store);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use log::{debug, error};
+use redb::{
+    Database, DatabaseError, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::blobs::BlobDir;
+use super::NodeStore;
+use crate::fs::defs::{FileKey, Superblock};
+use crate::fs::nodes::{INode, NameNode, Node};
+
+type Table = TableDefinition<'static, &'static [u8], &'static [u8]>;
+// A key set to a value or removed from a table
+type Change = (Table, Vec<u8>, Option<Vec<u8>>);
+
+// Keyed by the bincode of a Node
+const NODES: Table = TableDefinition::new("nodes");
+// Keyed by big endian inode numbers, holds the bincode of a Node
+const INODES: Table = TableDefinition::new("inodes");
+const NAME_NODES: Table = TableDefinition::new("name_nodes");
+const NAME_SETS: Table = TableDefinition::new("name_sets");
+const SUPERBLOCK: Table = TableDefinition::new("superblock");
+// Blobs to be renamed once the nodes referring to them by their new key are committed, keyed by
+// the new key
+const BLOB_RENAMES: Table = TableDefinition::new("blob_renames");
+// Blobs to be removed once the transaction that got rid of their nodes is committed
+const BLOB_REMOVALS: Table = TableDefinition::new("blob_removals");
+
+/// Keeps the nodes, name nodes and the inode index in a single database file (`nodes.redb`),
+/// the content of files stays in `blobs/`. `kv.lock` is locked while the store is open.
+///
+/// Writes pile up in one transaction until `commit`, so whatever was written in between either
+/// makes it to the disk as a whole or not at all. Blobs are renamed and removed along with it,
+/// which goes into the transaction and is done over if the store is opened before it was
+/// finished.
+pub struct KvStore {
+    db: Database,
+    _lock: File,
+    pending: RefCell<Option<WriteTransaction>>,
+    // Everything in the pending transaction, which is gone if committing it fails and has to be
+    // written over again
+    written: RefCell<Vec<Change>>,
+    blobs: BlobDir,
+}
+
+impl KvStore {
+    pub fn new(data_dir: &Path) -> io::Result<Self> {
+        let lock = super::lock_data_dir(data_dir, "kv")?;
+        let db = Database::create(data_dir.join("nodes.redb")).map_err(|e| match e {
+            // Only the lock file keeps out other processes, redb keeps out this one
+            DatabaseError::DatabaseAlreadyOpen => super::in_use(),
+            e => io_error(e),
+        })?;
+
+        // Tables only come to be when they're first opened for writing
+        let txn = db.begin_write().map_err(io_error)?;
+        for table in [
+            NODES,
+            INODES,
+            NAME_NODES,
+            NAME_SETS,
+            SUPERBLOCK,
+            BLOB_RENAMES,
+            BLOB_REMOVALS,
+        ] {
+            txn.open_table(table).map_err(io_error)?;
+        }
+        txn.commit().map_err(io_error)?;
+
+        let store = Self {
+            db,
+            _lock: lock,
+            pending: RefCell::new(None),
+            written: RefCell::new(Vec::new()),
+            blobs: BlobDir::new(&data_dir.join("blobs")),
+        };
+        let renames: Vec<(FileKey, FileKey)> = store
+            .keys(BLOB_RENAMES)
+            .into_iter()
+            .map(|to| {
+                let from = store.get(BLOB_RENAMES, &to).unwrap();
+                (from, bincode::deserialize(&to).unwrap())
+            })
+            .collect();
+        let removals: Vec<FileKey> = store
+            .keys(BLOB_REMOVALS)
+            .iter()
+            .map(|key| bincode::deserialize(key).unwrap())
+            .collect();
+        store.finish_blobs(&renames, &removals)?;
+
+        Ok(store)
+    }
+
+    fn finish_blobs(&self, renames: &[(FileKey, FileKey)], removals: &[FileKey]) -> io::Result<()> {
+        if renames.is_empty() && removals.is_empty() {
+            return Ok(());
+        }
+
+        self.blobs.apply_renames(renames)?;
+        self.blobs.apply_removals(removals)?;
+        let txn = self.db.begin_write().map_err(io_error)?;
+        {
+            let mut table = txn.open_table(BLOB_RENAMES).map_err(io_error)?;
+            for (_, to) in renames {
+                table
+                    .remove(bincode::serialize(to).unwrap().as_slice())
+                    .map_err(io_error)?;
+            }
+            let mut table = txn.open_table(BLOB_REMOVALS).map_err(io_error)?;
+            for key in removals {
+                table
+                    .remove(bincode::serialize(key).unwrap().as_slice())
+                    .map_err(io_error)?;
+            }
+        }
+        txn.commit().map_err(io_error)
+    }
+
+    // Reads see whatever has been written, committed or not
+    fn get<T: DeserializeOwned>(&self, table: Table, key: &[u8]) -> Option<T> {
+        let value = match self.pending.borrow().as_ref() {
+            Some(txn) => {
+                let table = txn.open_table(table).unwrap();
+                let value = table.get(key).unwrap();
+                value.map(|v| v.value().to_vec())
+            }
+            None => {
+                let txn = self.db.begin_read().unwrap();
+                let table = txn.open_table(table).unwrap();
+                let value = table.get(key).unwrap();
+                value.map(|v| v.value().to_vec())
+            }
+        };
+
+        value.map(|value| bincode::deserialize(&value).unwrap())
+    }
+
+    fn keys(&self, table: Table) -> Vec<Vec<u8>> {
+        fn collect(table: &impl ReadableTable<&'static [u8], &'static [u8]>) -> Vec<Vec<u8>> {
+            table
+                .iter()
+                .unwrap()
+                .map(|entry| entry.unwrap().0.value().to_vec())
+                .collect()
+        }
+
+        match self.pending.borrow().as_ref() {
+            Some(txn) => collect(&txn.open_table(table).unwrap()),
+            None => collect(&self.db.begin_read().unwrap().open_table(table).unwrap()),
+        }
+    }
+
+    fn write(&self, f: impl FnOnce(&WriteTransaction) -> io::Result<()>) -> io::Result<()> {
+        if self.pending.borrow().is_none() {
+            self.begin()?;
+        }
+        f(self.pending.borrow().as_ref().unwrap())
+    }
+
+    fn insert<T: Serialize + ?Sized>(&self, table: Table, key: &[u8], value: &T) -> io::Result<()> {
+        self.put(table, key, Some(bincode::serialize(value).unwrap()))
+    }
+
+    fn remove(&self, table: Table, key: &[u8]) -> io::Result<()> {
+        self.put(table, key, None)
+    }
+
+    fn put(&self, table: Table, key: &[u8], value: Option<Vec<u8>>) -> io::Result<()> {
+        self.write(|txn| apply(txn, table, key, value.as_deref()))?;
+        self.written.borrow_mut().push((table, key.to_vec(), value));
+        Ok(())
+    }
+
+    // Starts a new transaction, which takes over whatever one that failed to commit had in it
+    fn begin(&self) -> io::Result<()> {
+        let txn = self.db.begin_write().map_err(io_error)?;
+        for (table, key, value) in self.written.borrow().iter() {
+            apply(&txn, *table, key, value.as_deref())?;
+        }
+        *self.pending.borrow_mut() = Some(txn);
+        Ok(())
+    }
+}
+
+fn apply(txn: &WriteTransaction, table: Table, key: &[u8], value: Option<&[u8]>) -> io::Result<()> {
+    let mut table = txn.open_table(table).map_err(io_error)?;
+    match value {
+        Some(value) => table.insert(key, value).map_err(io_error)?,
+        None => table.remove(key).map_err(io_error)?,
+    };
+    Ok(())
+}
+
+fn io_error(e: impl Into<redb::Error>) -> io::Error {
+    io::Error::other(e.into())
+}
+
+fn node_key(node: &Node) -> Vec<u8> {
+    bincode::serialize(node).unwrap()
+}
+
+impl NodeStore for KvStore {
+    fn read_superblock(&self) -> io::Result<Option<Superblock>> {
+        Ok(self.get(SUPERBLOCK, b"superblock"))
+    }
+
+    fn write_superblock(&self, superblock: &Superblock) -> io::Result<()> {
+        self.insert(SUPERBLOCK, b"superblock", superblock)
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.pending.borrow().as_ref() {
+            Some(txn) => txn.open_table(INODES).unwrap().is_empty().unwrap(),
+            None => {
+                let txn = self.db.begin_read().unwrap();
+                let table = txn.open_table(INODES).unwrap();
+                table.is_empty().unwrap()
+            }
+        }
+    }
+
+    fn node_of(&self, ino: u64) -> Option<Node> {
+        self.get(INODES, &ino.to_be_bytes())
+    }
+
+    fn load_node(&self, node: &Node) -> Option<INode> {
+        self.get(NODES, &node_key(node))
+    }
+
+    fn store_node(&self, inode: &INode) -> io::Result<()> {
+        debug!("\tstore_node | {}", inode.to_node());
+
+        let node = inode.to_node();
+        self.insert(NODES, &node_key(&node), inode)?;
+        self.insert(INODES, &inode.attr().inode.to_be_bytes(), &node)
+    }
+
+    fn remove_node(&self, inode: &INode) -> io::Result<()> {
+        debug!("\tremove_node | {}", inode.to_node());
+
+        let node = inode.to_node();
+        let ino = inode.attr().inode.to_be_bytes();
+        // A rehashed file has its inode number pointing at the new key already
+        if self.get::<Node>(INODES, &ino).as_ref() == Some(&node) {
+            self.remove(INODES, &ino)?;
+        }
+        self.remove(NODES, &node_key(&node))
+    }
+
+    fn list_tags(&self) -> Vec<Uuid> {
+        self.keys(NODES)
+            .iter()
+            .filter_map(|key| match bincode::deserialize(key) {
+                Ok(Node::Tag(id)) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_files(&self) -> Vec<FileKey> {
+        self.keys(NODES)
+            .iter()
+            .filter_map(|key| match bincode::deserialize(key) {
+                Ok(Node::File(key)) => Some(key),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_symlinks(&self) -> Vec<Uuid> {
+        self.keys(NODES)
+            .iter()
+            .filter_map(|key| match bincode::deserialize(key) {
+                Ok(Node::Symlink(id)) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_inos(&self) -> Vec<u64> {
+        self.keys(INODES)
+            .iter()
+            .filter_map(|key| Some(u64::from_be_bytes(key.as_slice().try_into().ok()?)))
+            .collect()
+    }
+
+    fn forget_ino(&self, ino: u64) -> io::Result<()> {
+        self.remove(INODES, &ino.to_be_bytes())
+    }
+
+    fn load_name_node(&self, id: &Uuid) -> Option<NameNode> {
+        self.get(NAME_NODES, id.as_bytes())
+    }
+
+    fn store_name_node(&self, name_node: &NameNode) -> io::Result<()> {
+        debug!("\tstore_name_node | {name_node}");
+        self.insert(NAME_NODES, name_node.id.as_bytes(), name_node)
+    }
+
+    fn remove_name_node(&self, id: &Uuid) -> io::Result<()> {
+        self.remove(NAME_NODES, id.as_bytes())
+    }
+
+    fn list_name_nodes(&self) -> Vec<Uuid> {
+        self.keys(NAME_NODES)
+            .iter()
+            .filter_map(|key| Uuid::from_slice(key).ok())
+            .collect()
+    }
+
+    fn load_name_set(&self, name: &OsStr) -> BTreeSet<Uuid> {
+        self.get(NAME_SETS, name.as_bytes()).unwrap_or_default()
+    }
+
+    fn store_name_set(&self, name: &OsStr, ids: &BTreeSet<Uuid>) -> io::Result<()> {
+        if ids.is_empty() {
+            self.remove(NAME_SETS, name.as_bytes())
+        } else {
+            self.insert(NAME_SETS, name.as_bytes(), ids)
+        }
+    }
+
+    fn list_names(&self) -> Vec<OsString> {
+        self.keys(NAME_SETS)
+            .into_iter()
+            .map(OsString::from_vec)
+            .collect()
+    }
+
+    fn create_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.blobs.create(key)
+    }
+
+    fn read_blob(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.blobs.read(key, offset, size)
+    }
+
+    fn write_blob(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64> {
+        self.blobs.write(key, offset, data)
+    }
+
+    fn truncate_blob(&self, key: &FileKey, size: u64) -> io::Result<()> {
+        self.blobs.truncate(key, size)
+    }
+
+    fn blob_size(&self, key: &FileKey) -> Option<u64> {
+        self.blobs.size(key)
+    }
+
+    fn blob_reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>> {
+        self.blobs.reader(key)
+    }
+
+    fn rename_blob(&self, from: &FileKey, to: &FileKey) -> io::Result<()> {
+        self.blobs.rename(from, to)
+    }
+
+    fn remove_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.blobs.remove(key)
+    }
+
+    fn list_blobs(&self) -> Vec<FileKey> {
+        self.blobs.list()
+    }
+
+    fn commit(&self) -> io::Result<()> {
+        let renames = self.blobs.pending_renames();
+        for (from, to) in &renames {
+            self.insert(BLOB_RENAMES, &bincode::serialize(to).unwrap(), from)?;
+        }
+        let removals = self.blobs.pending_removals();
+        for key in &removals {
+            self.insert(BLOB_REMOVALS, &bincode::serialize(key).unwrap(), &())?;
+        }
+
+        if self.pending.borrow().is_none() && !self.written.borrow().is_empty() {
+            self.begin()?;
+        }
+        let txn = self.pending.borrow_mut().take();
+        if let Some(txn) = txn {
+            debug!("\tcommit | {} changes", self.written.borrow().len());
+            // The changes stay around for the next transaction to write over again
+            txn.commit().map_err(io_error)?;
+        }
+        self.written.borrow_mut().clear();
+        self.finish_blobs(&renames, &removals)
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("KvStore::commit | {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+    use crate::fs::nodes::TagNode;
+
+    #[test]
+    fn writes_outlive_a_commit_that_failed() {
+        let data_dir = std::env::temp_dir().join(format!("tagfs-kv-{}", std::process::id()));
+        let store = KvStore::new(&data_dir).unwrap();
+        let tag = INode::Tag(TagNode::new(2, None));
+        store.store_node(&tag).unwrap();
+
+        // A transaction that failed to commit is gone, as if it had been aborted
+        drop(store.pending.borrow_mut().take());
+        store.commit().unwrap();
+        drop(store);
+
+        let store = KvStore::new(&data_dir).unwrap();
+        assert_eq!(store.node_of(2), Some(tag.to_node()));
+        assert!(store.load_node(&tag.to_node()).is_some());
+
+        drop(store);
+        remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Cursor, Read};
+
+use libc::ENOENT;
+use uuid::Uuid;
+
+use super::NodeStore;
+use crate::fs::defs::{FileKey, Superblock};
+use crate::fs::nodes::{INode, NameNode, Node};
+
+/// Keeps everything in memory, it's all gone once the filesystem is unmounted.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    superblock: Option<Superblock>,
+    nodes: BTreeMap<Node, INode>,
+    inos: BTreeMap<u64, Node>,
+    name_nodes: BTreeMap<Uuid, NameNode>,
+    name_sets: BTreeMap<OsString, BTreeSet<Uuid>>,
+    blobs: BTreeMap<FileKey, Vec<u8>>,
+}
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(ENOENT)
+}
+
+impl NodeStore for MemoryStore {
+    fn read_superblock(&self) -> io::Result<Option<Superblock>> {
+        Ok(self.state.borrow().superblock.clone())
+    }
+
+    fn write_superblock(&self, superblock: &Superblock) -> io::Result<()> {
+        self.state.borrow_mut().superblock = Some(superblock.clone());
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.borrow().nodes.is_empty()
+    }
+
+    fn node_of(&self, ino: u64) -> Option<Node> {
+        self.state.borrow().inos.get(&ino).cloned()
+    }
+
+    fn load_node(&self, node: &Node) -> Option<INode> {
+        self.state.borrow().nodes.get(node).cloned()
+    }
+
+    fn store_node(&self, inode: &INode) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.inos.insert(inode.attr().inode, inode.to_node());
+        state.nodes.insert(inode.to_node(), inode.clone());
+        Ok(())
+    }
+
+    fn remove_node(&self, inode: &INode) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let node = inode.to_node();
+        let ino = inode.attr().inode;
+        if state.inos.get(&ino) == Some(&node) {
+            state.inos.remove(&ino);
+        }
+        state.nodes.remove(&node);
+        Ok(())
+    }
+
+    fn list_tags(&self) -> Vec<Uuid> {
+        self.state
+            .borrow()
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::Tag(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_files(&self) -> Vec<FileKey> {
+        self.state
+            .borrow()
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::File(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_symlinks(&self) -> Vec<Uuid> {
+        self.state
+            .borrow()
+            .nodes
+            .keys()
+            .filter_map(|node| match node {
+                Node::Symlink(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn list_inos(&self) -> Vec<u64> {
+        self.state.borrow().inos.keys().copied().collect()
+    }
+
+    fn forget_ino(&self, ino: u64) -> io::Result<()> {
+        self.state.borrow_mut().inos.remove(&ino);
+        Ok(())
+    }
+
+    fn load_name_node(&self, id: &Uuid) -> Option<NameNode> {
+        self.state.borrow().name_nodes.get(id).cloned()
+    }
+
+    fn store_name_node(&self, name_node: &NameNode) -> io::Result<()> {
+        self.state
+            .borrow_mut()
+            .name_nodes
+            .insert(name_node.id, name_node.clone());
+        Ok(())
+    }
+
+    fn remove_name_node(&self, id: &Uuid) -> io::Result<()> {
+        self.state.borrow_mut().name_nodes.remove(id);
+        Ok(())
+    }
+
+    fn list_name_nodes(&self) -> Vec<Uuid> {
+        self.state.borrow().name_nodes.keys().copied().collect()
+    }
+
+    fn load_name_set(&self, name: &OsStr) -> BTreeSet<Uuid> {
+        self.state
+            .borrow()
+            .name_sets
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn store_name_set(&self, name: &OsStr, ids: &BTreeSet<Uuid>) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if ids.is_empty() {
+            state.name_sets.remove(name);
+        } else {
+            state.name_sets.insert(name.to_os_string(), ids.clone());
+        }
+        Ok(())
+    }
+
+    fn list_names(&self) -> Vec<OsString> {
+        self.state.borrow().name_sets.keys().cloned().collect()
+    }
+
+    fn create_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.state
+            .borrow_mut()
+            .blobs
+            .insert(key.clone(), Vec::new());
+        Ok(())
+    }
+
+    fn read_blob(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let state = self.state.borrow();
+        let blob = state.blobs.get(key).ok_or_else(not_found)?;
+        let start = blob.len().min(offset as usize);
+        let end = blob.len().min(start + size as usize);
+        Ok(blob[start..end].to_vec())
+    }
+
+    fn write_blob(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.borrow_mut();
+        let blob = state.blobs.get_mut(key).ok_or_else(not_found)?;
+        let end = offset as usize + data.len();
+        if blob.len() < end {
+            blob.resize(end, 0);
+        }
+        blob[offset as usize..end].copy_from_slice(data);
+        Ok(blob.len() as u64)
+    }
+
+    fn truncate_blob(&self, key: &FileKey, size: u64) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let blob = state.blobs.get_mut(key).ok_or_else(not_found)?;
+        blob.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn blob_size(&self, key: &FileKey) -> Option<u64> {
+        self.state
+            .borrow()
+            .blobs
+            .get(key)
+            .map(|blob| blob.len() as u64)
+    }
+
+    fn blob_reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>> {
+        let blob = self.state.borrow().blobs.get(key).cloned();
+        Ok(Box::new(Cursor::new(blob.ok_or_else(not_found)?)))
+    }
+
+    fn rename_blob(&self, from: &FileKey, to: &FileKey) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let blob = state.blobs.remove(from).ok_or_else(not_found)?;
+        state.blobs.insert(to.clone(), blob);
+        Ok(())
+    }
+
+    fn remove_blob(&self, key: &FileKey) -> io::Result<()> {
+        self.state.borrow_mut().blobs.remove(key);
+        Ok(())
+    }
+
+    fn list_blobs(&self) -> Vec<FileKey> {
+        self.state.borrow().blobs.keys().cloned().collect()
+    }
+}
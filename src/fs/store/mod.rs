@@ -0,0 +1,282 @@
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use uuid::Uuid;
+
+use super::defs::{FileKey, Superblock, FORMAT_VERSION};
+use super::nodes::{INode, NameNode, Node, TagNode};
+
+pub use self::dir::DirStore;
+pub use self::image::{mkfs, ImageStore};
+pub use self::kv::KvStore;
+pub use self::memory::MemoryStore;
+
+mod blobs;
+mod dir;
+mod image;
+mod kv;
+mod memory;
+
+// Names of the backends that can be picked at mount time
+pub const BACKENDS: &[&str] = &["dir", "kv", "memory"];
+
+/// Where the nodes and the content of the files end up.
+///
+/// Every node is kept under its key (the `FileKey` of a file, the id of anything else) together with
+/// an index from inode numbers to nodes. Everything takes `&self`, the stores that need to
+/// mutate in memory do so on their own.
+pub trait NodeStore {
+    /// `None` if nothing has been written yet, an error if what's there can't be read.
+    fn read_superblock(&self) -> io::Result<Option<Superblock>>;
+    fn write_superblock(&self, superblock: &Superblock) -> io::Result<()>;
+    /// Whether there are no nodes at all, i.e. whether it's safe to create a new root.
+    fn is_empty(&self) -> bool;
+
+    fn node_of(&self, ino: u64) -> Option<Node>;
+    fn load_node(&self, node: &Node) -> Option<INode>;
+    /// Stores a node, pointing its inode number at it.
+    fn store_node(&self, inode: &INode) -> io::Result<()>;
+    /// Drops a node, its inode number only goes with it if it still points at this node.
+    fn remove_node(&self, inode: &INode) -> io::Result<()>;
+    fn list_tags(&self) -> Vec<Uuid>;
+    fn list_files(&self) -> Vec<FileKey>;
+    fn list_symlinks(&self) -> Vec<Uuid>;
+    fn list_inos(&self) -> Vec<u64>;
+    /// Drops an inode number whatever it points at, for the ones that lead nowhere anymore.
+    fn forget_ino(&self, ino: u64) -> io::Result<()>;
+
+    fn load_name_node(&self, id: &Uuid) -> Option<NameNode>;
+    fn store_name_node(&self, name_node: &NameNode) -> io::Result<()>;
+    fn remove_name_node(&self, id: &Uuid) -> io::Result<()>;
+    fn list_name_nodes(&self) -> Vec<Uuid>;
+    /// Ids of every name node going by a name, empty if there are none.
+    fn load_name_set(&self, name: &OsStr) -> BTreeSet<Uuid>;
+    /// Stores the ids going by a name, an empty set removes the name.
+    fn store_name_set(&self, name: &OsStr, ids: &BTreeSet<Uuid>) -> io::Result<()>;
+    fn list_names(&self) -> Vec<OsString>;
+
+    fn create_blob(&self, key: &FileKey) -> io::Result<()>;
+    /// Reads up to `size` bytes, less if the content ends before that.
+    fn read_blob(&self, key: &FileKey, offset: u64, size: u32) -> io::Result<Vec<u8>>;
+    /// Writes at an offset, returning the size of the content afterwards.
+    fn write_blob(&self, key: &FileKey, offset: u64, data: &[u8]) -> io::Result<u64>;
+    fn truncate_blob(&self, key: &FileKey, size: u64) -> io::Result<()>;
+    fn blob_size(&self, key: &FileKey) -> Option<u64>;
+    fn blob_reader(&self, key: &FileKey) -> io::Result<Box<dyn Read>>;
+    fn rename_blob(&self, from: &FileKey, to: &FileKey) -> io::Result<()>;
+    /// Content is gone right away as far as reads go, but stays on the disk until the nodes
+    /// that went without it are committed.
+    fn remove_blob(&self, key: &FileKey) -> io::Result<()>;
+    fn list_blobs(&self) -> Vec<FileKey>;
+
+    /// Makes everything written so far durable, there's nothing to do for stores that write
+    /// straight through. Writes that fail here are still around for the next commit to retry.
+    fn commit(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens the store of one of the `BACKENDS`, which fails while another process has it open.
+pub fn open(backend: &str, data_dir: &Path) -> io::Result<Box<dyn NodeStore>> {
+    Ok(match backend {
+        "dir" => Box::new(DirStore::new(data_dir)?),
+        "kv" => Box::new(KvStore::new(data_dir)?),
+        "memory" => Box::new(MemoryStore::default()),
+        _ => panic!("Unknown backend {backend}"),
+    })
+}
+
+// Keeps other processes from opening the store for as long as the file stays open
+fn lock(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::WouldBlock => Err(in_use()),
+        e => Err(e),
+    }
+}
+
+// Every backend locks a file of its own, so that migrate can open two of them in the same
+// data directory
+fn lock_data_dir(data_dir: &Path, backend: &str) -> io::Result<File> {
+    create_dir_all(data_dir)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(data_dir.join(format!("{backend}.lock")))?;
+    lock(&file)?;
+    Ok(file)
+}
+
+fn in_use() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "store is in use")
+}
+
+/// Puts the root tag into an empty store, along with the superblock pointing at it.
+pub fn format(store: &dyn NodeStore) -> io::Result<()> {
+    // The root is a fake tag holding everything (sort of like 'all tags') and has to get the
+    // root inode number
+    let root = TagNode::new(fuser::FUSE_ROOT_ID, None);
+    let superblock = Superblock {
+        version: FORMAT_VERSION,
+        root: root.id,
+        inode_next: fuser::FUSE_ROOT_ID + 1,
+    };
+    store.store_node(&INode::Tag(root))?;
+    store.write_superblock(&superblock)?;
+    store.commit()
+}
+
+/// Copies everything in one store over to another one, which has to be empty. Content that the
+/// target can already see (e.g. when both keep it in the same directory) is left alone.
+pub fn migrate(from: &dyn NodeStore, to: &dyn NodeStore) -> io::Result<()> {
+    let superblock = from
+        .read_superblock()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there's no store to migrate"))?;
+    if !to.is_empty() || to.read_superblock()?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the target store isn't empty",
+        ));
+    }
+
+    let nodes = from
+        .list_tags()
+        .into_iter()
+        .map(Node::Tag)
+        .chain(from.list_symlinks().into_iter().map(Node::Symlink))
+        .chain(from.list_files().into_iter().map(Node::File));
+    for node in nodes {
+        let Some(inode) = from.load_node(&node) else {
+            continue;
+        };
+        if let INode::File(f) = &inode {
+            let key = f.key();
+            if to.blob_size(&key).is_none() {
+                to.create_blob(&key)?;
+                io::copy(&mut from.blob_reader(&key)?, &mut BlobWriter::new(to, &key))?;
+            }
+        }
+        to.store_node(&inode)?;
+    }
+    for id in from.list_name_nodes() {
+        if let Some(name_node) = from.load_name_node(&id) {
+            to.store_name_node(&name_node)?;
+        }
+    }
+    for name in from.list_names() {
+        to.store_name_set(&name, &from.load_name_set(&name))?;
+    }
+
+    // Only once everything else is in, a half migrated store isn't mounted by accident
+    to.write_superblock(&superblock)?;
+    to.commit()
+}
+
+// Appends to a blob through a store
+struct BlobWriter<'a> {
+    store: &'a dyn NodeStore,
+    key: &'a FileKey,
+    offset: u64,
+}
+
+impl<'a> BlobWriter<'a> {
+    fn new(store: &'a dyn NodeStore, key: &'a FileKey) -> Self {
+        Self {
+            store,
+            key,
+            offset: 0,
+        }
+    }
+}
+
+impl io::Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.store.write_blob(self.key, self.offset, buf)?;
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::populated;
+
+    #[test]
+    fn migrating_copies_everything() {
+        let fs = populated();
+        let from = &*fs.store;
+        let to = MemoryStore::default();
+        migrate(from, &to).unwrap();
+
+        assert_eq!(
+            to.read_superblock().unwrap().unwrap().root,
+            from.read_superblock().unwrap().unwrap().root
+        );
+        assert_eq!(to.list_tags(), from.list_tags());
+        assert_eq!(to.list_files(), from.list_files());
+        assert_eq!(to.list_symlinks(), from.list_symlinks());
+        assert_eq!(to.list_inos(), from.list_inos());
+        assert_eq!(to.list_name_nodes(), from.list_name_nodes());
+        assert_eq!(to.list_names(), from.list_names());
+        for name in from.list_names() {
+            assert_eq!(to.load_name_set(&name), from.load_name_set(&name));
+        }
+        assert_eq!(to.list_blobs(), from.list_blobs());
+        for key in from.list_blobs() {
+            assert_eq!(
+                to.read_blob(&key, 0, 4096).unwrap(),
+                from.read_blob(&key, 0, 4096).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn migrating_needs_a_store_and_an_empty_target() {
+        let fs = populated();
+        let to = MemoryStore::default();
+        migrate(&*fs.store, &to).unwrap();
+
+        let e = migrate(&*fs.store, &to).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        let e = migrate(&MemoryStore::default(), &to).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn stores_are_only_opened_once_at_a_time() {
+        let data_dir = std::env::temp_dir().join(format!("tagfs-lock-{}", std::process::id()));
+        for backend in ["dir", "kv"] {
+            let store = open(backend, &data_dir).unwrap();
+            let e = open(backend, &data_dir).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+            drop(store);
+            open(backend, &data_dir).unwrap();
+        }
+
+        let image = data_dir.join("image");
+        mkfs(&image, Some(1 << 20)).unwrap();
+        let store = ImageStore::open(&image).unwrap();
+        let e = ImageStore::open(&image).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            mkfs(&image, None).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        drop(store);
+        ImageStore::open(&image).unwrap();
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use libc::{c_int, ENOENT};
+
+use super::defs::FileKind;
+use super::nodes::{INode, Node, TagNode};
+use super::{disambiguate, TagFS};
+
+// Views get inode numbers far above anything the store is ever going to hand out
+pub const VIEW_INO_BASE: u64 = 1 << 48;
+
+// Holds query directories for expressions that don't fit the shorthand syntax
+pub const QUERY_DIR: &str = ".query";
+
+// Opens files by the hash of their content, abbreviated like git does
+pub const BY_HASH_DIR: &str = ".by-hash";
+const MIN_HASH_PREFIX: usize = 4;
+
+// Every file in the store, and only the ones with no names outside of the root
+pub const ALL_DIR: &str = ".all";
+pub const UNTAGGED_DIR: &str = ".untagged";
+
+const OPERATORS: &[u8] = b"&+-|!()";
+const ESCAPE: u8 = b'\\';
+
+/// A boolean expression over tags, evaluated into the set of files it matches.
+///
+/// `a&b` (or `a+b`) intersects, `a|b` unions, `a-b` subtracts and `!a` complements against every
+/// file in the store. `&`, `+` and `-` bind tighter than `|`, parentheses group. A backslash makes
+/// the character after it part of the tag name, operators included.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub enum Query {
+    Tag(OsString),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    pub fn parse(text: &OsStr) -> Option<Self> {
+        let mut parser = Parser {
+            input: text.as_bytes(),
+            pos: 0,
+        };
+        let query = parser.expr()?;
+
+        (parser.pos == parser.input.len()).then_some(query)
+    }
+
+    // Plain names in the root are tags or files, only names with operators are worth parsing.
+    // Plenty of file names have a `+` or `-` in them, queries using those go under `.query`.
+    pub fn is_shorthand(name: &OsStr) -> bool {
+        let name = name.as_bytes();
+        !name.iter().any(|c| b"+-".contains(c)) && name.iter().any(|c| OPERATORS.contains(c))
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn expr(&mut self) -> Option<Query> {
+        let mut query = self.term()?;
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            query = Query::Or(Box::new(query), Box::new(self.term()?));
+        }
+
+        Some(query)
+    }
+
+    fn term(&mut self) -> Option<Query> {
+        let mut query = self.factor()?;
+        loop {
+            match self.peek() {
+                Some(b'&' | b'+') => {
+                    self.pos += 1;
+                    query = Query::And(Box::new(query), Box::new(self.factor()?));
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    let rhs = Query::Not(Box::new(self.factor()?));
+                    query = Query::And(Box::new(query), Box::new(rhs));
+                }
+                _ => return Some(query),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Option<Query> {
+        match self.peek()? {
+            b'!' => {
+                self.pos += 1;
+                Some(Query::Not(Box::new(self.factor()?)))
+            }
+            b'(' => {
+                self.pos += 1;
+                let query = self.expr()?;
+                if self.peek() != Some(b')') {
+                    return None;
+                }
+                self.pos += 1;
+                Some(query)
+            }
+            _ => {
+                let mut name = Vec::new();
+                while let Some(c) = self.peek() {
+                    if OPERATORS.contains(&c) {
+                        break;
+                    }
+                    self.pos += 1;
+                    if c == ESCAPE {
+                        // Nothing left to escape
+                        name.push(self.peek()?);
+                        self.pos += 1;
+                    } else {
+                        name.push(c);
+                    }
+                }
+                if name.is_empty() {
+                    return None;
+                }
+                Some(Query::Tag(OsStr::from_bytes(&name).into()))
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub enum View {
+    // The `.query` directory itself, it can't list the infinitely many queries it contains
+    QueryRoot,
+    Query(Query),
+    ByHash,
+    All,
+    Untagged,
+    // Files carrying every one of the tags, in faceted mode
+    Facet(BTreeSet<OsString>),
+}
+
+/// Inode numbers handed out to views so far, kept in memory only.
+#[derive(Default)]
+pub struct Views {
+    by_ino: BTreeMap<u64, View>,
+    by_view: BTreeMap<View, u64>,
+}
+
+impl Views {
+    pub fn get(&self, ino: u64) -> Option<&View> {
+        self.by_ino.get(&ino)
+    }
+
+    // The same view keeps the same inode number for as long as the filesystem is mounted
+    pub fn ino(&mut self, view: View) -> u64 {
+        if let Some(ino) = self.by_view.get(&view) {
+            return *ino;
+        }
+
+        let ino = VIEW_INO_BASE + self.by_ino.len() as u64;
+        self.by_ino.insert(ino, view.clone());
+        self.by_view.insert(view, ino);
+        ino
+    }
+}
+
+impl TagFS {
+    // Files and symlinks that have a name in the tag, subtags don't count
+    fn tagged_nodes(&self, tag_node: &TagNode) -> BTreeSet<Node> {
+        tag_node
+            .dir_links
+            .iter()
+            .filter_map(|id| self.get_name_node(id).ok())
+            .map(|n| n.link)
+            .filter(|link| !matches!(link, Node::Tag(_)))
+            .collect()
+    }
+
+    fn eval_query(&self, query: &Query, tag_nodes: &[TagNode]) -> Result<BTreeSet<Node>, c_int> {
+        Ok(match query {
+            Query::Tag(name) => {
+                let tag_node = self.find_tag(tag_nodes, name).ok_or(ENOENT)?;
+                self.tagged_nodes(tag_node)
+            }
+            Query::Not(query) => {
+                let all: BTreeSet<Node> = tag_nodes
+                    .iter()
+                    .flat_map(|t| self.tagged_nodes(t))
+                    .collect();
+                &all - &self.eval_query(query, tag_nodes)?
+            }
+            Query::And(lhs, rhs) => {
+                &self.eval_query(lhs, tag_nodes)? & &self.eval_query(rhs, tag_nodes)?
+            }
+            Query::Or(lhs, rhs) => {
+                &self.eval_query(lhs, tag_nodes)? | &self.eval_query(rhs, tag_nodes)?
+            }
+        })
+    }
+
+    /// Works out the content of a view, sorted by name so that readdir offsets stay put between
+    /// calls. Query results show a file under every distinct name it has in any tag, facets only
+    /// under the names it has in the tags they're made of. Files without any names at all go by
+    /// their key, i.e. their hash along with their inode number.
+    pub(super) fn view_entries(
+        &mut self,
+        view: &View,
+    ) -> Result<Vec<(OsString, u64, FileKind)>, c_int> {
+        let tag_nodes = self.list_tag_nodes();
+        let (matched, named_in) = match view {
+            View::QueryRoot => return Ok(Vec::new()),
+            View::ByHash => return Ok(self.hash_entries()),
+            View::Query(query) => (
+                self.eval_query(query, &tag_nodes)?,
+                tag_nodes.iter().collect(),
+            ),
+            View::All => (
+                self.list_file_nodes()
+                    .into_iter()
+                    .map(|f| Node::File(f.key()))
+                    .collect(),
+                tag_nodes.iter().collect(),
+            ),
+            View::Untagged => {
+                let root_links = match self.get_node(&Node::Tag(self.root))? {
+                    INode::Tag(t) => t.dir_links,
+                    _ => BTreeSet::new(),
+                };
+                let matched = self
+                    .list_file_nodes()
+                    .into_iter()
+                    .filter(|f| f.back_links.iter().all(|id| root_links.contains(id)))
+                    .map(|f| Node::File(f.key()))
+                    .collect();
+                (matched, tag_nodes.iter().collect())
+            }
+            View::Facet(names) => {
+                let facet = names
+                    .iter()
+                    .map(|name| self.find_tag(&tag_nodes, name).ok_or(ENOENT))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let matched = facet
+                    .iter()
+                    .map(|t| self.tagged_nodes(t))
+                    .reduce(|all, nodes| &all & &nodes)
+                    .unwrap_or_default();
+                (matched, facet)
+            }
+        };
+
+        let mut entries = BTreeMap::new();
+        let mut named = BTreeSet::new();
+        let mut subtags = BTreeSet::new();
+        for tag_node in named_in {
+            for id in &tag_node.dir_links {
+                let name_node = match self.get_name_node(id) {
+                    Ok(name_node) => name_node,
+                    Err(_) => continue,
+                };
+                if let Node::Tag(id) = name_node.link {
+                    subtags.insert(id);
+                    continue;
+                }
+                if !matched.contains(&name_node.link) {
+                    continue;
+                }
+                if let Ok(node) = self.get_node(&name_node.link) {
+                    let attr = node.attr();
+                    named.insert(name_node.link.clone());
+                    // Any of the names will do, as long as it's the same one every time
+                    entries
+                        .entry((name_node.name.clone(), attr.inode))
+                        .or_insert((Some(name_node), attr.kind));
+                }
+            }
+        }
+
+        for node in matched.difference(&named) {
+            if let (Node::File(key), Ok(node)) = (node, self.get_node(node)) {
+                let attr = node.attr();
+                entries.insert(
+                    (OsString::from(key.to_string()), attr.inode),
+                    (None, attr.kind),
+                );
+            }
+        }
+
+        // Facets offer every other tag that narrows them down further, along with the ones stored
+        // inside of them so that freshly made tags don't go missing
+        if let View::Facet(names) = view {
+            for tag_node in &tag_nodes {
+                let name_node = match self.tag_name_node(tag_node) {
+                    Some(n) if tag_node.id != self.root && !names.contains(&n.name) => n,
+                    _ => continue,
+                };
+                let name = name_node.name.clone();
+                if !subtags.contains(&tag_node.id)
+                    && self.tagged_nodes(tag_node).is_disjoint(&matched)
+                {
+                    continue;
+                }
+
+                let mut refined = names.clone();
+                refined.insert(name.clone());
+                let ino = self.views.ino(View::Facet(refined));
+                entries.insert((name, ino), (Some(name_node), FileKind::Directory));
+            }
+        }
+
+        Ok(disambiguate(entries.into_iter().map(
+            |((name, inode), (name_node, kind))| (name, name_node, inode, kind),
+        )))
+    }
+
+    /// The view a directory is listed as, if any. With facets turned on every tag below the root
+    /// is one too, narrowed down by whatever tags come after it in the path.
+    pub(super) fn directory_view(&self, ino: u64) -> Option<View> {
+        if let Some(view) = self.views.get(ino) {
+            return Some(view.clone());
+        }
+        if !self.faceted {
+            return None;
+        }
+
+        match self.get_inode(ino) {
+            Ok(INode::Tag(t)) if t.id != self.root => {
+                Some(View::Facet(BTreeSet::from([self.tag_name(&t)?])))
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolves a name that isn't stored anywhere, either a view under the root or a name inside
+    /// of a view.
+    pub(super) fn lookup_view(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<fuser::FileAttr, c_int> {
+        let view = match self.directory_view(parent) {
+            Some(View::QueryRoot) => View::Query(Query::parse(name).ok_or(ENOENT)?),
+            Some(View::ByHash) => {
+                let node = self.find_by_hash(name)?;
+                return Ok(self.inode_attr(node));
+            }
+            Some(view) => {
+                let (_, ino, _) = self
+                    .view_entries(&view)?
+                    .into_iter()
+                    .find(|(entry, _, _)| entry == name)
+                    .ok_or(ENOENT)?;
+                return match self.views.get(ino) {
+                    Some(_) => self.view_attr(ino),
+                    None => Ok(self.inode_attr(self.get_inode(ino)?)),
+                };
+            }
+            None if parent != fuser::FUSE_ROOT_ID => return Err(ENOENT),
+            None if name == QUERY_DIR => View::QueryRoot,
+            None if name == BY_HASH_DIR => View::ByHash,
+            None if name == ALL_DIR => View::All,
+            None if name == UNTAGGED_DIR => View::Untagged,
+            None if Query::is_shorthand(name) => View::Query(Query::parse(name).ok_or(ENOENT)?),
+            None => return Err(ENOENT),
+        };
+
+        // Queries naming tags that don't exist don't exist either
+        if let View::Query(query) = &view {
+            self.eval_query(query, &self.list_tag_nodes())?;
+        }
+
+        let ino = self.views.ino(view);
+        self.view_attr(ino)
+    }
+
+    fn hash_entries(&self) -> Vec<(OsString, u64, FileKind)> {
+        // Listed in order of their hashes already, files with the same content show up once
+        let mut files = self.list_file_nodes();
+        files.dedup_by(|f, first| f.hash == first.hash);
+        files
+            .into_iter()
+            .map(|f| (f.hash.code.into(), f.file_attr.inode, FileKind::File))
+            .collect()
+    }
+
+    /// Finds a file by its full hash or by a prefix of it, as long as the prefix is long enough
+    /// and only matches a single hash. Any of the files with that content will do.
+    fn find_by_hash(&self, name: &OsStr) -> Result<INode, c_int> {
+        let prefix = name.to_str().ok_or(ENOENT)?;
+        if prefix.len() < MIN_HASH_PREFIX || !prefix.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ENOENT);
+        }
+        let prefix = prefix.to_ascii_lowercase();
+
+        let mut matches = self
+            .list_file_nodes()
+            .into_iter()
+            .filter(|f| f.hash.code.starts_with(&prefix));
+        let first = matches.next().ok_or(ENOENT)?;
+        if matches.any(|f| f.hash != first.hash) {
+            return Err(ENOENT);
+        }
+        Ok(INode::File(first))
+    }
+
+    // Views are read-only directories that look just like the root
+    pub(super) fn view_attr(&self, ino: u64) -> Result<fuser::FileAttr, c_int> {
+        let mut attr = self.get_node(&Node::Tag(self.root))?.into_attr();
+        attr.inode = ino;
+        attr.size = 0;
+        attr.mode = 0o555;
+        attr.hardlinks = 2;
+
+        Ok(attr.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::{create, mounted, ROOT};
+
+    fn parse(text: &str) -> Option<Query> {
+        Query::parse(text.as_ref())
+    }
+
+    fn tag(name: &str) -> Box<Query> {
+        Box::new(Query::Tag(name.into()))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a|b&c"),
+            Some(Query::Or(
+                tag("a"),
+                Box::new(Query::And(tag("b"), tag("c")))
+            ))
+        );
+        assert_eq!(
+            parse("(a|b)+c"),
+            Some(Query::And(
+                Box::new(Query::Or(tag("a"), tag("b"))),
+                tag("c")
+            ))
+        );
+        assert_eq!(
+            parse("a-b&c"),
+            Some(Query::And(
+                Box::new(Query::And(tag("a"), Box::new(Query::Not(tag("b"))))),
+                tag("c")
+            ))
+        );
+        assert_eq!(
+            parse("!a&b"),
+            Some(Query::And(Box::new(Query::Not(tag("a"))), tag("b")))
+        );
+    }
+
+    #[test]
+    fn escaped_operators_are_part_of_the_name() {
+        assert_eq!(parse(r"rock\-n\-roll"), Some(*tag("rock-n-roll")));
+        assert_eq!(parse(r"a\&b|c"), Some(Query::Or(tag("a&b"), tag("c"))));
+        assert_eq!(parse(r"\(a\)"), Some(*tag("(a)")));
+        assert_eq!(parse(r"back\\slash"), Some(*tag(r"back\slash")));
+    }
+
+    #[test]
+    fn empty_terms_are_malformed() {
+        for text in ["", "a&", "&a", "a||b", "()", "a-", "!", "a+(b|)"] {
+            assert_eq!(parse(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        for text in ["(a", "a)", "(a|b))", "a\\", "a(b)", "!!"] {
+            assert_eq!(parse(text), None, "{text:?}");
+        }
+        // As deep as a name can get
+        assert_eq!(parse(&"(".repeat(255)), None);
+        assert_eq!(parse(&"!".repeat(255)), None);
+    }
+
+    #[test]
+    fn names_with_plus_or_minus_in_the_root_are_files() {
+        let mut fs = mounted();
+        create(&mut fs, ROOT, "a", FileKind::Directory);
+        create(&mut fs, ROOT, "b", FileKind::Directory);
+
+        assert_eq!(fs.lookup_view(ROOT, "a-b".as_ref()), Err(ENOENT));
+        assert_eq!(fs.lookup_view(ROOT, "a+b".as_ref()), Err(ENOENT));
+        let file = create(&mut fs, ROOT, "a-b", FileKind::File);
+        let (_, inode) = fs
+            .search_name(&fs.get_tag_node(ROOT).unwrap(), "a-b".as_ref())
+            .unwrap();
+        assert_eq!(inode.attr().inode, file);
+
+        // Still queries elsewhere
+        assert!(fs.lookup_view(ROOT, "a&b".as_ref()).is_ok());
+        let query_root = fs.lookup_view(ROOT, QUERY_DIR.as_ref()).unwrap().ino;
+        assert!(fs.lookup_view(query_root, "a-b".as_ref()).is_ok());
+    }
+}
@@ -1,30 +1,378 @@
-#![feature(map_first_last)]
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
 use fuser::MountOption;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
 
 mod fs;
 
 fn main() {
     let matches = App::new("tag_fs")
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("MOUNT_POINT")
                 .required(true)
                 .index(1)
                 .help("Act as a client, and mount FUSE at given path"),
         )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .default_value("/tmp/tagfs")
+                .help("Directory the nodes and file contents are stored in"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .possible_values(fs::store::BACKENDS)
+                .default_value("dir")
+                .help("How the nodes are stored, memory keeps them only until unmounting"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .value_name("FILE")
+                .conflicts_with_all(&["data-dir", "backend"])
+                .help("Mount an image made by mkfs (a file or a block device) instead"),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .short("r")
+                .conflicts_with("gc-interval")
+                .help("Mount the filesystem read-only"),
+        )
+        .arg(
+            Arg::with_name("allow-other")
+                .long("allow-other")
+                .conflicts_with("allow-root")
+                .help("Allow all users to access the filesystem"),
+        )
+        .arg(
+            Arg::with_name("allow-root")
+                .long("allow-root")
+                .help("Allow root to access the filesystem in addition to the mounting user"),
+        )
+        .arg(
+            Arg::with_name("default-permissions")
+                .long("default-permissions")
+                .help("Let the kernel check permissions against file modes"),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .long("daemonize")
+                .short("d")
+                .help("Detach from the terminal instead of running in the foreground"),
+        )
+        .arg(
+            Arg::with_name("fsname")
+                .long("fsname")
+                .value_name("NAME")
+                .default_value("tag_fs")
+                .help("Name of the filesystem source shown in mtab"),
+        )
+        .arg(
+            Arg::with_name("faceted")
+                .long("faceted")
+                .help("Treat nested tags as an intersection, /a/b lists files tagged both a and b"),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .value_name("NODES")
+                .default_value("4096")
+                .validator(|size| match size.parse::<usize>() {
+                    Ok(size) if size > 0 => Ok(()),
+                    _ => Err("has to be a positive number".to_string()),
+                })
+                .help("How many nodes to keep in memory, changes are written back when evicted"),
+        )
+        .arg(
+            Arg::with_name("gc-interval")
+                .long("gc-interval")
+                .value_name("SECONDS")
+                .validator(|secs| match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Ok(()),
+                    _ => Err("has to be a positive number".to_string()),
+                })
+                .help(
+                    "Remove unreachable nodes at most this often, checked whenever changes are written",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copy a store over to another backend, the old one is left as it is")
+                .arg(
+                    Arg::with_name("data-dir")
+                        .long("data-dir")
+                        .value_name("DIR")
+                        .default_value("/tmp/tagfs")
+                        .help("Directory the nodes and file contents are stored in"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("BACKEND")
+                        .possible_values(&["dir", "kv"])
+                        .default_value("dir")
+                        .help("Backend the store is in now"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("BACKEND")
+                        .possible_values(&["dir", "kv"])
+                        .default_value("kv")
+                        .help("Backend to move the store to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mkfs")
+                .about("Format a file or a block device as an empty image")
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("File or block device to format, files are created if needed"),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .value_name("SIZE")
+                        .validator(|size| parse_size(&size).map(|_| ()))
+                        .help("Size of the image, with an optional K, M, G or T suffix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check that the nodes of an unmounted store agree with each other")
+                .arg(
+                    Arg::with_name("data-dir")
+                        .long("data-dir")
+                        .value_name("DIR")
+                        .default_value("/tmp/tagfs")
+                        .help("Directory the nodes and file contents are stored in"),
+                )
+                .arg(
+                    Arg::with_name("backend")
+                        .long("backend")
+                        .value_name("BACKEND")
+                        .possible_values(&["dir", "kv"])
+                        .default_value("dir")
+                        .help("How the nodes are stored"),
+                )
+                .arg(
+                    Arg::with_name("image")
+                        .long("image")
+                        .value_name("FILE")
+                        .conflicts_with_all(&["data-dir", "backend"])
+                        .help("Check an image made by mkfs instead"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix what can be fixed instead of only reporting it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove whatever can't be reached from the root tag of an unmounted store")
+                .arg(
+                    Arg::with_name("data-dir")
+                        .long("data-dir")
+                        .value_name("DIR")
+                        .default_value("/tmp/tagfs")
+                        .help("Directory the nodes and file contents are stored in"),
+                )
+                .arg(
+                    Arg::with_name("backend")
+                        .long("backend")
+                        .value_name("BACKEND")
+                        .possible_values(&["dir", "kv"])
+                        .default_value("dir")
+                        .help("How the nodes are stored"),
+                )
+                .arg(
+                    Arg::with_name("image")
+                        .long("image")
+                        .value_name("FILE")
+                        .conflicts_with_all(&["data-dir", "backend"])
+                        .help("Collect in an image made by mkfs instead"),
+                ),
+        )
         .get_matches();
     env_logger::init();
 
-    let mountpoint = matches.value_of("MOUNT_POINT").unwrap();
-    // TODO: In the future, switch to RW filesystem, choose sync or async i/o, allow execution of
-    // binaries
-    let options = vec![
-        MountOption::RW,
-        MountOption::FSName("tag_fs".to_string()),
+    if let ("mkfs", Some(matches)) = matches.subcommand() {
+        let image = PathBuf::from(matches.value_of("IMAGE").unwrap());
+        let size = matches
+            .value_of("size")
+            .map(|size| parse_size(size).unwrap());
+        if let Err(e) = fs::store::mkfs(&image, size) {
+            eprintln!("Could not format {image:?}: {e}");
+            exit(1);
+        }
+        println!("Formatted {image:?}");
+        return;
+    }
+
+    if let ("migrate", Some(matches)) = matches.subcommand() {
+        let data_dir = PathBuf::from(matches.value_of("data-dir").unwrap());
+        let (from, to) = (
+            matches.value_of("from").unwrap(),
+            matches.value_of("to").unwrap(),
+        );
+        if from == to {
+            eprintln!("Nothing to migrate, the store is in {from} already");
+            exit(1);
+        }
+
+        let open = |backend| match fs::store::open(backend, &data_dir) {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("Could not open {data_dir:?} with {backend}: {e}");
+                exit(1);
+            }
+        };
+        if let Err(e) = fs::store::migrate(open(from).as_ref(), open(to).as_ref()) {
+            eprintln!("Could not migrate {data_dir:?} from {from} to {to}: {e}");
+            exit(1);
+        }
+        println!("Migrated {data_dir:?} from {from} to {to}, mount it with --backend {to}");
+        return;
+    }
+
+    if let ("fsck", Some(matches)) = matches.subcommand() {
+        let report =
+            match fs::fsck::fsck(open_store(matches).as_ref(), matches.is_present("repair")) {
+                Ok(report) => report,
+                Err(e) => {
+                    // Like fsck(8), 8 for an operational error
+                    eprintln!("Could not write the repairs: {e}");
+                    exit(8);
+                }
+            };
+        if report.problems == 0 {
+            println!("No problems found");
+            return;
+        }
+        println!(
+            "{} problems found, {} repaired",
+            report.problems, report.repaired
+        );
+        // Like fsck(8), 1 when everything was fixed and 4 when something is left
+        exit(if report.repaired == report.problems {
+            1
+        } else {
+            4
+        });
+    }
+
+    if let ("gc", Some(matches)) = matches.subcommand() {
+        // Nothing is open in a store that isn't mounted
+        match fs::gc::collect(open_store(matches).as_ref(), |_| false) {
+            Ok(collected) => {
+                println!(
+                    "Removed {} nodes, {} names, {} blobs and {} inode numbers",
+                    collected.nodes, collected.names, collected.blobs, collected.inos
+                );
+                if collected.lost_tags > 0 {
+                    println!(
+                        "Kept {} tags that lost their names, run fsck --repair to put them back",
+                        collected.lost_tags
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not collect garbage: {e}");
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut mountpoint = PathBuf::from(matches.value_of("MOUNT_POINT").unwrap());
+
+    // TODO: choose sync or async i/o, allow execution of binaries
+    let mut options = vec![
+        MountOption::FSName(matches.value_of("fsname").unwrap().to_string()),
         MountOption::AutoUnmount,
-        MountOption::AllowOther,
     ];
-    let fs = fs::TagFS::new();
+    if matches.is_present("read-only") {
+        options.push(MountOption::RO);
+    } else {
+        options.push(MountOption::RW);
+    }
+    if matches.is_present("allow-other") {
+        options.push(MountOption::AllowOther);
+    }
+    if matches.is_present("allow-root") {
+        options.push(MountOption::AllowRoot);
+    }
+    if matches.is_present("default-permissions") {
+        options.push(MountOption::DefaultPermissions);
+    }
+
+    let fs = fs::TagFS::new(
+        open_store(&matches),
+        matches.is_present("read-only"),
+        matches.is_present("faceted"),
+        value_t!(matches, "cache-size", NonZeroUsize).unwrap(),
+        value_t!(matches, "gc-interval", u64)
+            .ok()
+            .map(Duration::from_secs),
+    );
+
+    if matches.is_present("daemonize") {
+        // The daemon changes its working directory to /, so a relative mount point would break
+        mountpoint = mountpoint.canonicalize().unwrap();
+        if unsafe { libc::daemon(0, 0) } != 0 {
+            panic!("Could not daemonize: {}", std::io::Error::last_os_error());
+        }
+    }
+
     fuser::mount2(fs, mountpoint, &options).unwrap();
 }
+
+// The store picked by --image, or --data-dir and --backend
+fn open_store(matches: &ArgMatches) -> Box<dyn fs::store::NodeStore> {
+    match matches.value_of("image") {
+        Some(image) => match fs::store::ImageStore::open(Path::new(image)) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                eprintln!("Could not open {image:?}: {e}");
+                exit(1);
+            }
+        },
+        None => {
+            let data_dir = Path::new(matches.value_of("data-dir").unwrap());
+            match fs::store::open(matches.value_of("backend").unwrap(), data_dir) {
+                Ok(store) => store,
+                Err(e) => {
+                    eprintln!("Could not open {data_dir:?}: {e}");
+                    exit(1);
+                }
+            }
+        }
+    }
+}
+
+// Sizes like 512, 64K or 2G
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K' | 'k') => (&size[..size.len() - 1], 10),
+        Some('M' | 'm') => (&size[..size.len() - 1], 20),
+        Some('G' | 'g') => (&size[..size.len() - 1], 30),
+        Some('T' | 't') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("{size} is not a size"))
+}